@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::logger;
+use crate::utils::consts;
+
+/// Runtime settings of kdata, read from `kdata.toml` or `kdata.json` next to the game,
+/// or from the file `KCONFIG` points to. Everything is optional.
+///
+/// ```toml
+/// resource_dir = "."
+/// cache_dir = "windata/.ac_movie_sc"
+/// log_level = "debug"
+/// debug = true
+/// mmap = false
+/// hot_reload = true
+/// override_dir = "patch"
+/// trace = "kdata_trace.json"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Folder with resource.bin and the resource_patch_*.bin applied on top of it
+    pub resource_dir: PathBuf,
+
+    /// Hidden folder movies are decrypted into, suffixed per load. The game plays them from its parent
+    pub cache_dir: PathBuf,
+
+    /// error, warn, info, debug or trace, `KDEBUG=<level>` takes precedence
+    pub log_level: Option<String>,
+
+    /// Same as setting `KDEBUG`
+    pub debug: bool,
+
+    /// Map the packs instead of reading them, always off in debug mode. Packs too large
+    /// for the 32-bit address space of the game are read either way
+    pub mmap: bool,
+
+    /// Reload the packs once kpack rebuilds them, debug mode only
+    pub hot_reload: bool,
+
+    /// Loose files served instead of the packed ones, debug mode only. `KPATCH` takes precedence
+    pub override_dir: PathBuf,
+
+    /// Record every file the game looks up into this file on release, for `kpack coverage`
+    pub trace: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            resource_dir: PathBuf::from("."),
+            cache_dir: PathBuf::from("windata/.ac_movie_sc"),
+            log_level: None,
+            debug: false,
+            mmap: false,
+            hot_reload: true,
+            override_dir: PathBuf::from("patch"),
+            trace: None,
+        }
+    }
+}
+
+impl Config {
+    /// The config of `dir`, the defaults when there is none.
+    pub fn load(dir: &Path) -> Result<Self> {
+        if let Some(path) = std::env::var_os("KCONFIG") {
+            return Self::load_file(Path::new(&path));
+        }
+
+        match consts::CONFIG_PATHS.iter().map(|e| dir.join(e)).find(|e| e.is_file()) {
+            Some(path) => Self::load_file(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn load_file(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Ok(toml::from_str(&buf)?),
+            Some("json") => Ok(serde_json::from_str(&buf)?),
+            _ => Err(anyhow!("Unknown config format: {:?}", path)),
+        }
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.debug || std::env::var_os("KDEBUG").is_some()
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        if std::env::var_os("KDEBUG").is_some() {
+            return logger::env_level();
+        }
+
+        match self.log_level.as_deref().and_then(|e| e.parse().ok()) {
+            Some(level) => level,
+            None if self.debug => LevelFilter::Debug,
+            None => LevelFilter::Info,
+        }
+    }
+
+    pub fn override_dir(&self) -> PathBuf {
+        std::env::var_os("KPATCH").map_or_else(|| self.override_dir.clone(), PathBuf::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config::load(dir.path())?;
+        assert_eq!(config.resource_dir, Path::new("."));
+        assert!(!config.mmap);
+
+        std::fs::write(dir.path().join("kdata.toml"), "log_level = \"trace\"\nmmap = true\n")?;
+        let config = Config::load(dir.path())?;
+        assert_eq!(config.log_level.as_deref(), Some("trace"));
+        assert!(config.mmap);
+        assert_eq!(config.cache_dir, Path::new("windata/.ac_movie_sc"));
+
+        std::fs::write(dir.path().join("kdata.toml"), "unknown = 1\n")?;
+        assert!(Config::load(dir.path()).is_err());
+        Ok(())
+    }
+}
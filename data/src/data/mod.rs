@@ -11,6 +11,8 @@ pub mod mdf;
 pub mod psb;
 
 pub mod resource;
+pub mod stack;
+pub mod unpack;
 pub mod helper;
 
 /// Read a byte as size, then read that many bytes and convert to u32 via little endian.
@@ -1,22 +1,27 @@
 #![allow(clippy::ptr_arg)]
 
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 use std::io::{BufWriter, Cursor};
 use std::io::SeekFrom;
 use std::mem::size_of_val;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use binrw::{BinRead, BinReaderExt, BinResult, binrw, BinWrite, BinWriterExt};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use indexmap::IndexMap;
+use log::{debug, warn};
+use md5::{Digest, Md5};
+use rayon::prelude::*;
 
-use crate::utils::{self, consts, get_body_from_info, get_entry_key};
-use crate::utils::{generate_xor_key_from_seed, xor_data};
+use crate::utils::{self, consts, copy_chunked, get_body_from_info, get_entry_uid, CountingWriter};
+use crate::utils::cipher::{Cipher, CipherType, CipherWriter};
 
 use super::helper::{KBuf, KString};
-
-static mut FILE_ENTRY_COUNTER: u32 = 0;
+use super::unpack::UnpackRule;
 
 #[binrw]
 #[derive(Debug, Clone, PartialEq)]
@@ -26,6 +31,88 @@ pub enum FSType {
     Unpack,
 }
 
+#[binrw]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[brw(repr = u8)]
+pub enum Compression {
+    None = 0,
+    Deflate,
+}
+
+impl Compression {
+    pub fn encoder<W: Write>(&self, writer: W) -> Encoder<W> {
+        match self {
+            Compression::None => Encoder::None(writer),
+            Compression::Deflate => Encoder::Deflate(DeflateEncoder::new(writer, flate2::Compression::default())),
+        }
+    }
+
+    pub fn decoder<R: Read>(&self, reader: R) -> Decoder<R> {
+        match self {
+            Compression::None => Decoder::None(reader),
+            Compression::Deflate => Decoder::Deflate(DeflateDecoder::new(reader)),
+        }
+    }
+
+    /// Decompress `src` into `dst`, which must be exactly the uncompressed size.
+    pub fn decompress(&self, src: &[u8], dst: &mut [u8]) -> std::io::Result<()> {
+        match self {
+            Compression::None => {
+                dst.copy_from_slice(src);
+                Ok(())
+            }
+            Compression::Deflate => DeflateDecoder::new(src).read_exact(dst),
+        }
+    }
+}
+
+/// Streaming counterpart of `Compression::decompress`.
+pub enum Encoder<W: Write> {
+    None(W),
+    Deflate(DeflateEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Flush the remaining compressed data and return the inner writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Deflate(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Deflate(e) => e.flush(),
+        }
+    }
+}
+
+/// Reading counterpart of `Encoder`.
+pub enum Decoder<R: Read> {
+    None(R),
+    Deflate(DeflateDecoder<R>),
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Decoder::None(r) => r.read(buf),
+            Decoder::Deflate(d) => d.read(buf),
+        }
+    }
+}
+
 #[binrw]
 #[derive(Debug, Clone)]
 pub struct FileEntry {
@@ -37,29 +124,141 @@ pub struct FileEntry {
 
     pub name: KString,
 
-    pub offset: u32,
-    pub size: u32,
+    pub offset: u64,
+    /// Uncompressed size, this is what the game sees.
+    pub size: u64,
 
-    pub real_offset: u32,
+    pub real_offset: u64,
+    /// Uid whose key encrypts the payload at `real_offset`.
+    /// Differs from `uid` when the payload is shared with an identical entry.
+    pub blob_uid: u32,
+
+    /// md5 of the plain payload, filled by `Resource::scan_sources`.
+    pub hash: [u8; 16],
+
+    pub compression: Compression,
+    /// Size of the payload inside resource.bin, filled by `Resource::scan_sources`.
+    pub stored_size: u64,
+
+    /// Host file of a standalone entry, only used while packing.
+    #[brw(ignore)]
+    pub source: Option<PathBuf>,
 }
 
 impl FileEntry {
-    pub fn new(ty: FSType, base: String, name: String, offset: u32, size: u32) -> Self {
-        let idx = unsafe {
-            FILE_ENTRY_COUNTER += 1;
-            FILE_ENTRY_COUNTER
-        };
-
+    /// The uid is left unassigned until `Resource::assign_uids` is called.
+    pub fn new(ty: FSType, base: String, name: String, offset: u64, size: u64) -> Self {
         Self {
-            uid: idx,
+            uid: 0,
             ty,
             name: name.into(),
             base: base.into(),
             offset,
             size,
             real_offset: 0,
+            blob_uid: 0,
+            hash: [0u8; 16],
+            compression: Compression::None,
+            stored_size: size,
+            source: None,
         }
     }
+
+    /// An entry packed from a file on disk, `name` is its virtual path such as `movies/op.mzv`.
+    /// Only the virtual path ends up in resource.bin.
+    pub fn standalone(name: &str, source: PathBuf, size: u64) -> Self {
+        let (base, file) = name.rsplit_once('/').unwrap_or(("", name));
+        let mut ret = Self::new(FSType::Unpack, base.to_string(), file.to_string(), 0, size);
+        ret.source = Some(source);
+        ret
+    }
+
+    /// Whether the payload at `real_offset` is written for this entry, not borrowed from another one.
+    pub fn owns_blob(&self) -> bool {
+        self.blob_uid == self.uid
+    }
+
+    /// Whether this entry would produce the same encrypted payload as `other`.
+    pub fn same_source(&self, other: &FileEntry) -> bool {
+        self.uid == other.uid
+            && self.blob_uid == other.blob_uid
+            && self.ty == other.ty
+            && self.base.data == other.base.data
+            && self.name.data == other.name.data
+            && self.offset == other.offset
+            && self.size == other.size
+            && self.hash == other.hash
+            && self.compression == other.compression
+            && self.stored_size == other.stored_size
+    }
+}
+
+/// Keeps the source files open across entries, most entries share a few `_body.bin`.
+struct SourceFiles<'a> {
+    base_files: &'a IndexMap<String, PathBuf>,
+    handles: HashMap<PathBuf, File>,
+}
+
+impl<'a> SourceFiles<'a> {
+    fn new(base_files: &'a IndexMap<String, PathBuf>) -> Self {
+        Self { base_files, handles: HashMap::new() }
+    }
+
+    /// The file holding the payload of `entry`, positioned at its first byte.
+    fn open(&mut self, entry: &FileEntry) -> std::io::Result<&mut File> {
+        let path = if let Some(v) = &entry.source {
+            v.clone()
+        } else if let Some(v) = self.base_files.get(&entry.base.data) {
+            get_body_from_info(v).unwrap()
+        } else {
+            // Base file not record, thus the raw binary file
+            PathBuf::from(&entry.base.data)
+        };
+
+        self.open_at(&path, entry.offset)
+    }
+
+    fn open_at(&mut self, path: &Path, pos: u64) -> std::io::Result<&mut File> {
+        let file = match self.handles.entry(path.to_path_buf()) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let file = File::open(e.key())?;
+                e.insert(file)
+            }
+        };
+
+        file.seek(SeekFrom::Start(pos))?;
+        Ok(file)
+    }
+}
+
+/// Outcome of `Resource::retain_changed`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiffSummary {
+    /// Differs from the original entry
+    pub changed: usize,
+    /// Not in the original archive
+    pub added: usize,
+    /// Identical to the original entry, dropped
+    pub unchanged: usize,
+}
+
+/// Outcome of `Resource::apply_overlay`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OverlaySummary {
+    /// Entries packed from the overlay dir
+    pub applied: usize,
+    /// Files of the overlay dir replacing no entry, e.g. misspelled, as virtual paths
+    pub unmatched: Vec<String>,
+}
+
+/// Encrypted payloads of a previous build which can be copied verbatim.
+#[derive(Debug, Clone, Default)]
+pub struct ReuseMap {
+    pub path: PathBuf,
+
+    /// motion/ac_logo.psb.m => absolute offset of the payload in `path`
+    pub offsets: HashMap<String, u64>,
 }
 
 
@@ -83,11 +282,28 @@ pub struct Resource {
     #[bw(assert(is_finished.starts_with(consts::RESOURCE_DAT_MAGIC)))]
     pub is_finished: [u8; consts::RESOURCE_DAT_MAGIC.len()],
 
+    /// Bumped whenever the layout of the header or `FileEntry` changes.
+    #[br(assert(version == consts::RESOURCE_DAT_VERSION, "unsupported resource version: {}", version))]
+    #[bw(calc = consts::RESOURCE_DAT_VERSION)]
+    pub version: u32,
+
+    /// Cipher of the key, the index and all payloads.
+    pub cipher: CipherType,
+
+    #[bw(calc = rules.len() as u32)]
+    pub rule_cnt: u32,
+
+    /// Post-processing of the files extracted to disk at runtime.
+    #[br(count = rule_cnt)]
+    pub rules: Vec<UnpackRule>,
+
     // "motion" => name idx
     #[brw(ignore)]
     pub base_files: IndexMap<String, PathBuf>,
 
+    #[br(args(cipher))]
     #[br(parse_with = Resource::read_key)]
+    #[bw(args(*cipher))]
     #[bw(write_with = Resource::write_key)]
     pub key: String,
 
@@ -95,17 +311,21 @@ pub struct Resource {
     pub file_cnt: u32,
 
     /// motion/ac_logo.psb.m => FileEntry
-    #[bw(args(& key))]
+    #[bw(args(*cipher, & key))]
     #[bw(write_with = Resource::write_files)]
-    #[br(args(& key, file_cnt as usize))]
+    #[br(args(cipher, & key, file_cnt as usize))]
     #[br(parse_with = Resource::read_files)]
     pub files: IndexMap<String, FileEntry>,
 
     #[bw(write_with = Resource::write_current_position)]
     pub end_of_header: u64,
 
+    /// Set by `Resource::plan_reuse` for incremental packing.
+    #[brw(ignore)]
+    pub reuse: Option<ReuseMap>,
+
     #[bw(write_with = Resource::write_data)]
-    #[bw(args(& key, & base_files, & files))]
+    #[bw(args(*cipher, & key, & base_files, & files, & reuse))]
     pub raw_data: (),
 }
 
@@ -113,10 +333,13 @@ impl Default for Resource {
     fn default() -> Self {
         Self {
             is_finished: [0u8; consts::RESOURCE_DAT_MAGIC.len()],
+            cipher: CipherType::default(),
+            rules: Vec::new(),
             base_files: IndexMap::new(),
             key: String::new(),
             files: IndexMap::new(),
             end_of_header: 0,
+            reuse: None,
             raw_data: (),
         }
     }
@@ -127,38 +350,224 @@ impl Resource {
         self.base_files.insert(base_file_name, base_file_path);
     }
 
-    pub fn calc_offsets(&mut self) -> u32 {
+    /// Add an entry under the virtual path `name`, which must not be taken yet.
+    pub fn insert_file(&mut self, name: String, entry: FileEntry) -> Result<()> {
+        match self.files.entry(name) {
+            indexmap::map::Entry::Occupied(e) => Err(anyhow!("Duplicate virtual path: {}", e.key())),
+            indexmap::map::Entry::Vacant(e) => {
+                e.insert(entry);
+                Ok(())
+            }
+        }
+    }
+
+    /// Move the bases and entries of `other` into this one, failing on virtual path collisions.
+    pub fn merge(&mut self, other: Resource) -> Result<()> {
+        self.base_files.extend(other.base_files);
+        for (name, entry) in other.files {
+            self.insert_file(name, entry)?;
+        }
+        Ok(())
+    }
+
+    pub fn entry_cipher(&self, uid: u32) -> Result<Box<dyn Cipher>> {
+        self.cipher.entry_cipher(&self.key, uid)
+    }
+
+    /// Pack archive entries from `dir` instead when it has a file of the same virtual path,
+    /// e.g. `dir/script/a.txt.scn.m` replaces `script/a.txt.scn.m` of `script_body.bin`.
+    pub fn apply_overlay(&mut self, dir: &Path) -> Result<OverlaySummary> {
+        let mut ret = OverlaySummary::default();
+        for (name, path) in list_files(dir, "")? {
+            let Some(v) = self.files.get_mut(&name).filter(|v| v.ty == FSType::Embedded) else {
+                ret.unmatched.push(name);
+                continue;
+            };
+
+            debug!("Overlay: {name}");
+            let size = std::fs::metadata(&path)?.len();
+            let compression = v.compression;
+            *v = FileEntry::standalone(&name, path, size);
+            v.compression = compression;
+            ret.applied += 1;
+        }
+
+        Ok(ret)
+    }
+
+    /// Drop the entries whose payload is identical to the entry of the same virtual path in `original`.
+    /// Both must have been scanned by `scan_sources`.
+    pub fn retain_changed(&mut self, original: &Resource) -> DiffSummary {
+        let mut ret = DiffSummary::default();
+
+        self.files.retain(|name, v| match original.files.get(name) {
+            Some(o) if o.size == v.size && o.hash == v.hash => {
+                debug!("Unchanged: {name}");
+                ret.unchanged += 1;
+                false
+            }
+            Some(_) => {
+                ret.changed += 1;
+                true
+            }
+            None => {
+                ret.added += 1;
+                true
+            }
+        });
+
+        ret
+    }
+
+    /// Sort the entries by virtual path and derive each uid from its path,
+    /// so adding or removing a file never changes the uid (and thus the key) of the others.
+    pub fn assign_uids(&mut self) {
+        self.files.sort_keys();
+
+        let mut used = HashSet::with_capacity(self.files.len());
+        for (name, v) in self.files.iter_mut() {
+            let mut salt = 0;
+            let mut uid = get_entry_uid(name, salt);
+            while !used.insert(uid) {
+                salt += 1;
+                uid = get_entry_uid(name, salt);
+            }
+            v.uid = uid;
+            v.blob_uid = uid;
+        }
+    }
+
+    /// Lay out all entries back to back, returns the total size of the data section.
+    /// Entries with identical payloads share one blob, encrypted with the key of the first of them.
+    /// Must be called after `scan_sources` when compression or deduplication is wanted.
+    pub fn calc_offsets(&mut self) -> Result<u64> {
         self.is_finished.copy_from_slice(consts::RESOURCE_DAT_MAGIC);
 
-        let mut offset = 0;
-        for v in self.files.values_mut() {
+        self.assign_uids();
+
+        // (hash, size, compression, stored_size) => (real_offset, blob_uid)
+        let mut blobs = HashMap::new();
+
+        let mut offset: u64 = 0;
+        for (name, v) in self.files.iter_mut() {
+            // Not scanned, the content is unknown.
+            if v.hash != [0u8; 16] {
+                let blob = (v.hash, v.size, v.compression, v.stored_size);
+                if let Some(&(real_offset, blob_uid)) = blobs.get(&blob) {
+                    debug!("Duplicate payload: {name}");
+                    v.real_offset = real_offset;
+                    v.blob_uid = blob_uid;
+                    continue;
+                }
+                blobs.insert(blob, (offset, v.uid));
+            }
+
             v.real_offset = offset;
-            offset += v.size;
+            offset = offset
+                .checked_add(v.stored_size)
+                .ok_or_else(|| anyhow!("Data section overflow at {name}: {offset} + {}", v.stored_size))?;
         }
 
-        offset
+        Ok(offset)
+    }
+
+    /// Bytes not written thanks to deduplication, valid after `calc_offsets`.
+    pub fn dedup_saved(&self) -> u64 {
+        self.files.values().filter(|v| !v.owns_blob()).map(|v| v.stored_size).sum()
+    }
+
+    /// Hash the plain payload of every entry and measure its stored size.
+    /// Entries that do not shrink are stored without compression.
+    /// Runs on the current rayon thread pool.
+    pub fn scan_sources(&mut self) -> Result<()> {
+        let Resource { base_files, files, .. } = self;
+
+        files
+            .par_iter_mut()
+            .map_init(
+                || (SourceFiles::new(base_files), vec![0u8; consts::PACK_CHUNK_SIZE]),
+                |(sources, buf), (name, v)| Self::scan_entry(sources, buf, name, v),
+            )
+            .collect()
+    }
+
+    fn scan_entry(sources: &mut SourceFiles, buf: &mut [u8], name: &str, v: &mut FileEntry) -> Result<()> {
+        let mut file = sources.open(v)?.take(v.size);
+
+        let mut hasher = Md5::new();
+        let mut encoder = v.compression.encoder(CountingWriter::default());
+        let mut copied = 0;
+        loop {
+            let n = file.read(buf)?;
+            if n == 0 { break; }
+            hasher.update(&buf[..n]);
+            encoder.write_all(&buf[..n])?;
+            copied += n as u64;
+        }
+
+        if copied != v.size {
+            return Err(anyhow!("Unexpected end of source for {name}: {copied} of {} bytes", v.size));
+        }
+
+        v.hash = hasher.finalize().into();
+        v.stored_size = encoder.finish()?.count;
+
+        if v.compression != Compression::None && v.stored_size >= v.size {
+            debug!("Not compressible, store as is: {name}");
+            v.compression = Compression::None;
+            v.stored_size = v.size;
+        }
+
+        Ok(())
+    }
+
+    /// Mark entries whose source is unchanged since `prev` (read from `prev_path`) to be copied
+    /// instead of re-encrypted. Must be called after `scan_sources` and `calc_offsets`.
+    /// Returns the number of reusable entries.
+    pub fn plan_reuse(&mut self, prev: &Resource, prev_path: PathBuf) -> usize {
+        if prev.key != self.key || prev.cipher != self.cipher {
+            warn!("Encrypt key or cipher changed, nothing can be reused from {:?}", prev_path);
+            self.reuse = None;
+            return 0;
+        }
+
+        let mut reuse = ReuseMap {
+            path: prev_path,
+            ..Default::default()
+        };
+
+        for (name, v) in self.files.iter() {
+            match prev.files.get(name) {
+                Some(p) if v.same_source(p) => {
+                    reuse.offsets.insert(name.clone(), prev.end_of_header + p.real_offset);
+                }
+                _ => debug!("Changed: {name}"),
+            }
+        }
+
+        let cnt = reuse.offsets.len();
+        self.reuse = Some(reuse);
+        cnt
     }
 }
 
 impl Resource {
     #[binrw::writer(writer, endian)]
-    fn write_key(key: &String) -> BinResult<()> {
+    fn write_key(key: &String, cipher: CipherType) -> BinResult<()> {
         let mut buf = key.as_bytes().to_vec();
-        let keys = generate_xor_key_from_seed(consts::LOGO, 233).expect("get xor key failed");
-        xor_data(&mut buf, &keys);
+        cipher.key_cipher().expect("get key cipher failed").apply(&mut buf, 0);
         (buf.len() as u32).write_le(writer)?;
         buf.write_le(writer)?;
         Ok(())
     }
 
     #[binrw::parser(reader, endian)]
-    fn read_key() -> BinResult<String> {
+    fn read_key(cipher: CipherType) -> BinResult<String> {
         let sz = <u32>::read_options(reader, endian, ())? as usize;
         let mut buf = vec![0u8; sz];
         reader.read_exact(&mut buf)?;
 
-        let keys = generate_xor_key_from_seed(consts::LOGO, 233).expect("get xor key failed");
-        xor_data(&mut buf, &keys);
+        cipher.key_cipher().expect("get key cipher failed").apply(&mut buf, 0);
 
         let ret = String::from_utf8(buf).expect("invalid data");
 
@@ -166,18 +575,16 @@ impl Resource {
     }
 
     #[binrw::writer(writer, endian)]
-    fn write_files(files: &IndexMap<String, FileEntry>, key: &str) -> BinResult<()> {
-        let keys = generate_xor_key_from_seed(key, 114514).expect("Cannot generate key");
-
-        for (key, value) in files.iter() {
-            let key = KString::from(key.clone());
+    fn write_files(files: &IndexMap<String, FileEntry>, cipher: CipherType, key: &str) -> BinResult<()> {
+        for (i, (name, value)) in files.iter().enumerate() {
+            let name = KString::from(name.clone());
             let mut buf = Vec::new();
             let mut bw = Cursor::new(&mut buf);
 
-            key.write_le(&mut bw)?;
+            name.write_le(&mut bw)?;
             value.write_le(&mut bw)?;
 
-            xor_data(&mut buf, &keys);
+            cipher.index_cipher(key, i as u32).expect("Cannot generate key").apply(&mut buf, 0);
 
             writer.write_le(&KBuf::from(buf))?;
         }
@@ -186,15 +593,21 @@ impl Resource {
     }
 
     #[binrw::parser(reader, endian)]
-    fn read_files(key: &String, cnt: usize) -> BinResult<IndexMap<String, FileEntry>> {
-        let keys = generate_xor_key_from_seed(key, 114514)
-            .expect("Cannot generate key");
-
+    fn read_files(cipher: CipherType, key: &String, cnt: usize) -> BinResult<IndexMap<String, FileEntry>> {
         let mut ret = IndexMap::new();
 
-        for _ in 0..cnt {
+        // The legacy keystream does not depend on the record, generate it only once.
+        let legacy = match cipher {
+            CipherType::Legacy => Some(cipher.index_cipher(key, 0).expect("Cannot generate key")),
+            _ => None,
+        };
+
+        for i in 0..cnt {
             let mut buf = KBuf::read_le(reader)?;
-            xor_data(&mut buf.data, &keys);
+            match &legacy {
+                Some(v) => v.apply(&mut buf.data, 0),
+                None => cipher.index_cipher(key, i as u32).expect("Cannot generate key").apply(&mut buf.data, 0),
+            }
 
             let mut br = Cursor::new(&mut buf.data);
 
@@ -209,31 +622,91 @@ impl Resource {
     #[binrw::writer(writer, endian)]
     fn write_data(
         _: &(),
+        cipher: CipherType,
         key: &String,
         base_files: &IndexMap<String, PathBuf>,
         files: &IndexMap<String, FileEntry>,
+        reuse: &Option<ReuseMap>,
     ) -> BinResult<()> {
-        for (file, entry) in files.iter() {
-            let keys = generate_xor_key_from_seed(&get_entry_key(key, entry.uid), 114514).expect("Cannot generate key");
-
-            let FileEntry { uid, ty, base, name, offset, size, mut real_offset } = &entry;
+        // Deduplicated entries point into the blob of another entry, nothing to write.
+        let entries: Vec<_> = files.iter().filter(|(_, v)| v.owns_blob()).collect();
+        let window_size = consts::PACK_WINDOW_SIZE as u64;
+
+        let mut sources = SourceFiles::new(base_files);
+        let mut buf = vec![0u8; consts::PACK_CHUNK_SIZE];
+
+        let mut i = 0;
+        while i < entries.len() {
+            // Consecutive small entries are encrypted in parallel into memory, then written in order.
+            let mut j = i;
+            let mut window = 0;
+            while j < entries.len() && window + entries[j].1.stored_size <= window_size {
+                window += entries[j].1.stored_size;
+                j += 1;
+            }
+
+            if j == i {
+                // Too large to be buffered, stream it.
+                let (file, entry) = entries[i];
+                Self::write_entry(&mut sources, &mut buf, cipher, key, reuse, file, entry, &mut *writer)?;
+                i += 1;
+                continue;
+            }
+
+            let payloads = entries[i..j]
+                .par_iter()
+                .map_init(
+                    || (SourceFiles::new(base_files), vec![0u8; consts::PACK_CHUNK_SIZE]),
+                    |(sources, buf), (file, entry)| {
+                        let mut out = Vec::with_capacity(entry.stored_size as usize);
+                        Self::write_entry(sources, buf, cipher, key, reuse, file, entry, &mut out)?;
+                        Ok(out)
+                    },
+                )
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            for payload in payloads {
+                writer.write_all(&payload)?;
+            }
+            i = j;
+        }
 
-            let mut file = if let Some(v) = base_files.get(&base.data) {
-                let path = get_body_from_info(v).unwrap();
-                File::open(&path)?
-            } else {
-                // Base file not record, thus the raw binary file
-                File::open(&base.data)?
-            };
+        Ok(())
+    }
 
-            file.seek(SeekFrom::Start(*offset as u64))?;
+    /// Write the stored payload of one entry: copied from the previous pack, or read, compressed and encrypted.
+    #[allow(clippy::too_many_arguments)]
+    fn write_entry<W: Write>(
+        sources: &mut SourceFiles,
+        buf: &mut [u8],
+        cipher: CipherType,
+        key: &str,
+        reuse: &Option<ReuseMap>,
+        file: &str,
+        entry: &FileEntry,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        let FileEntry { name, size, compression, stored_size, .. } = entry;
+
+        // Already encrypted in the previous pack, copy as is.
+        if let Some((path, pos)) = reuse.as_ref().and_then(|r| r.offsets.get(file).map(|pos| (&r.path, *pos))) {
+            let mut prev = sources.open_at(path, pos)?.take(*stored_size);
+            let copied = copy_chunked(&mut prev, writer, buf)?;
+            if copied != *stored_size {
+                return Err(std::io::Error::other(format!("Previous pack truncated at {file}")));
+            }
+            return Ok(());
+        }
 
-            let mut buf = vec![0u8; *size as usize];
-            file.read_exact(&mut buf)?;
+        let entry_cipher = cipher.entry_cipher(key, entry.blob_uid).expect("Cannot generate key");
 
-            xor_data(&mut buf, &keys);
-            writer.write_le(&buf)?;
+        let mut src = sources.open(entry)?.take(*size);
+        let mut encoder = compression.encoder(CipherWriter::new(&mut *writer, entry_cipher));
+        let copied = copy_chunked(&mut src, &mut encoder, buf)?;
+        let written = encoder.finish()?.pos;
 
+        if copied != *size || written != *stored_size {
+            return Err(std::io::Error::other(format!("Source changed since scanned: {}", name.data)));
         }
 
         Ok(())
@@ -247,6 +720,22 @@ impl Resource {
     }
 }
 
+/// Files below `dir` as sorted `(virtual path, path)`, e.g. `script/a.txt.scn.m`, with `prefix` prepended.
+fn list_files(dir: &Path, prefix: &str) -> Result<Vec<(String, PathBuf)>> {
+    let mut ret = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            ret.append(&mut list_files(&entry.path(), &format!("{name}/"))?);
+        } else {
+            ret.push((name, entry.path()));
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
 #[cfg(test)]
 mod test {
     use std::io::{BufWriter, Cursor};
@@ -298,7 +787,8 @@ mod test {
             let file = std::fs::File::create(&res_path)?;
             let mut writer = BufWriter::new(file);
 
-            resource.calc_offsets();
+            resource.scan_sources()?;
+            resource.calc_offsets()?;
             resource.write(&mut writer)?;
         }
 
@@ -310,4 +800,287 @@ mod test {
 
         Ok(())
     }
+    #[test]
+    fn test_calc_offsets_overflow() {
+        let mut resource = Resource::default();
+        for (name, size) in [("a", u64::MAX - 1), ("b", 1), ("c", 1)] {
+            let entry = FileEntry::new(FSType::Unpack, name.to_string(), name.to_string(), 0, size);
+            resource.files.insert(name.to_string(), entry);
+        }
+
+        assert!(resource.calc_offsets().is_err());
+
+        resource.files.pop();
+        assert_eq!(resource.calc_offsets().unwrap(), u64::MAX);
+        assert_eq!(resource.files["b"].real_offset, u64::MAX - 1);
+    }
+
+    #[test]
+    fn test_uid_stable() {
+        let mut resource = Resource::default();
+        for name in ["motion/b.psb.m", "motion/a.psb.m"] {
+            let entry = FileEntry::new(FSType::Embedded, "motion".to_string(), name.to_string(), 0, 1);
+            resource.files.insert(name.to_string(), entry);
+        }
+        resource.assign_uids();
+        let before: Vec<_> = resource.files.values().map(|v| v.uid).collect();
+
+        let entry = FileEntry::new(FSType::Embedded, "motion".to_string(), "0.psb.m".to_string(), 0, 1);
+        resource.files.insert("motion/0.psb.m".to_string(), entry);
+        resource.assign_uids();
+
+        assert_eq!(resource.files.keys().collect::<Vec<_>>(), ["motion/0.psb.m", "motion/a.psb.m", "motion/b.psb.m"]);
+        assert_eq!(resource.files["motion/a.psb.m"].uid, before[0]);
+        assert_eq!(resource.files["motion/b.psb.m"].uid, before[1]);
+        assert!(resource.files.values().all(|v| v.uid != 0 && v.uid & 0x8000_0000 == 0));
+    }
+
+    fn pack_files(dir: &std::path::Path, out: &str, prev: Option<&str>) -> Result<Vec<u8>> {
+        let mut resource = Resource {
+            key: "test".to_string(),
+            ..Default::default()
+        };
+
+        for name in ["a.bin", "b.bin"] {
+            let path = dir.join(name);
+            let size = std::fs::metadata(&path)?.len();
+            let entry = FileEntry::new(FSType::Unpack, path.to_str().unwrap().to_string(), name.to_string(), 0, size);
+            resource.files.insert(name.to_string(), entry);
+        }
+
+        resource.scan_sources()?;
+        resource.calc_offsets()?;
+
+        if let Some(prev) = prev {
+            let prev_path = dir.join(prev);
+            let prev = Resource::read(&mut BufReader::new(File::open(&prev_path)?))?;
+            assert_eq!(resource.plan_reuse(&prev, prev_path), 1);
+        }
+
+        let out = dir.join(out);
+        resource.write(&mut BufWriter::new(File::create(&out)?))?;
+        Ok(std::fs::read(out)?)
+    }
+
+    #[test]
+    fn test_incremental() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.bin"), b"hello")?;
+        std::fs::write(dir.path().join("b.bin"), b"world")?;
+        pack_files(dir.path(), "v1.bin", None)?;
+
+        std::fs::write(dir.path().join("b.bin"), b"WORLD!")?;
+        let full = pack_files(dir.path(), "full.bin", None)?;
+        let incremental = pack_files(dir.path(), "incremental.bin", Some("v1.bin"))?;
+
+        assert_eq!(full, incremental);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let plain = b"Anonymous;Code ".repeat(64);
+        let src = dir.path().join("a.txt.scn.m");
+        std::fs::write(&src, &plain)?;
+
+        let mut resource = Resource {
+            key: "test".to_string(),
+            cipher: CipherType::ChaCha20,
+            ..Default::default()
+        };
+        let mut entry = FileEntry::new(FSType::Unpack, src.to_str().unwrap().to_string(), "a.txt.scn.m".to_string(), 0, plain.len() as u64);
+        entry.compression = Compression::Deflate;
+        resource.files.insert("script/a.txt.scn.m".to_string(), entry);
+
+        resource.scan_sources()?;
+        resource.calc_offsets()?;
+
+        let out = dir.path().join("resource.bin");
+        resource.write(&mut BufWriter::new(File::create(&out)?))?;
+
+        let res = Resource::read(&mut BufReader::new(File::open(&out)?))?;
+        let entry = &res.files["script/a.txt.scn.m"];
+        assert_eq!(entry.compression, Compression::Deflate);
+        assert!(entry.stored_size < entry.size);
+
+        let data = std::fs::read(&out)?;
+        let start = (res.end_of_header + entry.real_offset) as usize;
+        let mut stored = data[start..start + entry.stored_size as usize].to_vec();
+        res.entry_cipher(entry.blob_uid)?.apply(&mut stored, 0);
+
+        let mut buf = vec![0u8; entry.size as usize];
+        entry.compression.decompress(&stored, &mut buf)?;
+        assert_eq!(buf, plain);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_chunked() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut seed = 1u32;
+        let body: Vec<u8> = (0..consts::PACK_CHUNK_SIZE * 3)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8 % 16
+            })
+            .collect();
+        let src = dir.path().join("script_body.bin");
+        std::fs::write(&src, &body)?;
+
+        // All read from the same source file, through one handle per thread.
+        let mut resource = Resource {
+            key: "test".to_string(),
+            cipher: CipherType::ChaCha20,
+            ..Default::default()
+        };
+        let spans = [
+            ("a", 0, consts::PACK_CHUNK_SIZE * 2 + 5, Compression::None),
+            ("b", 100, consts::PACK_CHUNK_SIZE + 1, Compression::Deflate),
+            ("c", 3, 10, Compression::None),
+        ];
+        for (name, offset, size, compression) in spans {
+            let mut entry = FileEntry::new(FSType::Unpack, src.to_str().unwrap().to_string(), name.to_string(), offset as u64, size as u64);
+            entry.compression = compression;
+            resource.files.insert(name.to_string(), entry);
+        }
+
+        resource.scan_sources()?;
+        resource.calc_offsets()?;
+        let out = dir.path().join("resource.bin");
+        resource.write(&mut BufWriter::new(File::create(&out)?))?;
+
+        let res = Resource::read(&mut BufReader::new(File::open(&out)?))?;
+        let data = std::fs::read(&out)?;
+        for (name, offset, size, compression) in spans {
+            let entry = &res.files[name];
+            let plain = &body[offset..offset + size];
+            let start = (res.end_of_header + entry.real_offset) as usize;
+            let mut stored = data[start..start + entry.stored_size as usize].to_vec();
+            assert_eq!(entry.compression, compression);
+
+            // Written chunk by chunk, decoded in one go.
+            res.entry_cipher(entry.blob_uid)?.apply(&mut stored, 0);
+            let mut buf = vec![0u8; size];
+            entry.compression.decompress(&stored, &mut buf)?;
+            assert_eq!(buf, plain, "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut resource = Resource {
+            key: "test".to_string(),
+            ..Default::default()
+        };
+
+        for (name, data) in [("a.mzv", &b"same movie"[..]), ("a_en.mzv", b"same movie"), ("b.mzv", b"other movie")] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data)?;
+            let entry = FileEntry::new(FSType::Unpack, path.to_str().unwrap().to_string(), name.to_string(), 0, data.len() as u64);
+            resource.files.insert(name.to_string(), entry);
+        }
+
+        resource.scan_sources()?;
+        let total = resource.calc_offsets()?;
+        assert_eq!(total, 21);
+        assert_eq!(resource.dedup_saved(), 10);
+
+        let out = dir.path().join("resource.bin");
+        resource.write(&mut BufWriter::new(File::create(&out)?))?;
+
+        let res = Resource::read(&mut BufReader::new(File::open(&out)?))?;
+        let (a, a_en) = (&res.files["a.mzv"], &res.files["a_en.mzv"]);
+        assert_eq!(a.real_offset, a_en.real_offset);
+        assert_ne!(a.uid, a_en.uid);
+
+        let data = std::fs::read(&out)?;
+        assert_eq!(data.len() as u64, res.end_of_header + total);
+        for entry in [a, a_en] {
+            let start = (res.end_of_header + entry.real_offset) as usize;
+            let mut buf = data[start..start + entry.size as usize].to_vec();
+            res.entry_cipher(entry.blob_uid)?.apply(&mut buf, 0);
+            assert_eq!(buf, b"same movie");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("script"))?;
+        std::fs::write(dir.path().join("script/a.txt.scn.m"), b"translated")?;
+
+        let mut resource = Resource::default();
+        for name in ["a.txt.scn.m", "b.txt.scn.m"] {
+            let entry = FileEntry::new(FSType::Embedded, "script".to_string(), name.to_string(), 0x100, 4);
+            resource.files.insert(format!("script/{name}"), entry);
+        }
+
+        std::fs::write(dir.path().join("script/typo.txt.scn.m"), b"lost")?;
+        std::fs::create_dir(dir.path().join("scenario"))?;
+        std::fs::write(dir.path().join("scenario/a.txt.scn.m"), b"wrong base")?;
+
+        let summary = resource.apply_overlay(dir.path())?;
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.unmatched, ["scenario/a.txt.scn.m", "script/typo.txt.scn.m"]);
+
+        let a = &resource.files["script/a.txt.scn.m"];
+        assert_eq!((&a.ty, a.offset, a.size), (&FSType::Unpack, 0, 10));
+        assert_eq!(a.source, Some(dir.path().join("script/a.txt.scn.m")));
+        assert_eq!((a.base.data.as_str(), a.name.data.as_str()), ("script", "a.txt.scn.m"));
+
+        let b = &resource.files["script/b.txt.scn.m"];
+        assert_eq!((&b.ty, b.offset, b.base.data.as_str()), (&FSType::Embedded, 0x100, "script"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retain_changed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let scanned = |sub: &str, files: &[(&str, &[u8])]| -> Result<Resource> {
+            let mut ret = Resource::default();
+            std::fs::create_dir(dir.path().join(sub))?;
+            for (name, data) in files {
+                let path = dir.path().join(sub).join(name);
+                std::fs::write(&path, data)?;
+                let entry = FileEntry::new(FSType::Unpack, path.to_str().unwrap().to_string(), name.to_string(), 0, data.len() as u64);
+                ret.files.insert(name.to_string(), entry);
+            }
+            ret.scan_sources()?;
+            Ok(ret)
+        };
+
+        let original = scanned("original", &[("a", b"same"), ("b", b"old")])?;
+        let mut resource = scanned("patched", &[("a", b"same"), ("b", b"new"), ("c", b"added")])?;
+
+        let summary = resource.retain_changed(&original);
+        assert_eq!(summary, DiffSummary { changed: 1, added: 1, unchanged: 1 });
+        assert_eq!(resource.files.keys().collect::<Vec<_>>(), ["b", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_standalone() -> Result<()> {
+        let mut resource = Resource::default();
+        let entry = FileEntry::standalone("movies/op.mzv", PathBuf::from("/host/op.mzv"), 1);
+        assert_eq!((entry.base.data.as_str(), entry.name.data.as_str()), ("movies", "op.mzv"));
+        resource.insert_file("movies/op.mzv".to_string(), entry)?;
+
+        let mut other = Resource::default();
+        other.insert_file("op.mzv".to_string(), FileEntry::standalone("op.mzv", PathBuf::from("/host/op.mzv"), 1))?;
+        resource.merge(other)?;
+
+        let mut other = Resource::default();
+        other.insert_file("movies/op.mzv".to_string(), FileEntry::standalone("movies/op.mzv", PathBuf::from("/host/op_en.mzv"), 1))?;
+        assert!(resource.merge(other).is_err());
+
+        // The host path is never written.
+        let mut buf = Vec::new();
+        resource.files["op.mzv"].write_le(&mut Cursor::new(&mut buf))?;
+        assert!(!buf.windows(4).any(|e| e == b"host"));
+        Ok(())
+    }
 }
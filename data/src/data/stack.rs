@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use binrw::BinRead;
+use binrw::io::{BufReader, Cursor};
+use indexmap::IndexMap;
+use log::warn;
+use memmap2::Mmap;
+
+use crate::utils::consts;
+
+use super::resource::{FileEntry, Resource};
+
+/// How the payloads of a pack are read at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// Seek and read on a shared file handle.
+    #[default]
+    File,
+
+    /// Map the whole pack, entries are sliced out of the mapping without any syscall.
+    /// Keeps the file locked on Windows, so kpack can't replace it while loaded.
+    /// Packs too large for the address space, or failing to map, are read with `File` instead.
+    Mmap,
+}
+
+/// A loaded resource file.
+#[derive(Debug)]
+pub struct Pack {
+    pub path: PathBuf,
+    pub resource: Resource,
+
+    /// Set when loaded with `Backend::Mmap`.
+    pub map: Option<Mmap>,
+}
+
+impl Pack {
+    /// `len` bytes at `pos` of the mapped pack, `None` if not mapped.
+    pub fn slice(&self, pos: u64, len: usize) -> Option<Result<&[u8]>> {
+        let map = self.map.as_ref()?;
+        let range = usize::try_from(pos).ok().and_then(|start| Some(start..start.checked_add(len)?));
+        Some(range.and_then(|e| map.get(e)).ok_or_else(|| anyhow!("Read past the end of {:?}", self.path)))
+    }
+}
+
+/// Several packs layered on top of each other, later packs override entries of earlier ones.
+///
+/// ref: resource.bin <- resource_patch_01.bin <- resource_patch_02.bin
+#[derive(Debug, Default)]
+pub struct ResourceStack {
+    pub packs: Vec<Pack>,
+
+    /// motion/ac_logo.psb.m => index of the pack serving it
+    pub index: IndexMap<String, usize>,
+}
+
+impl ResourceStack {
+    /// `resource.bin` followed by all `resource_patch_*.bin` in `dir`, in the order they are applied:
+    /// by their number, so `_2` comes before `_10`, then the ones without a number by name.
+    pub fn discover(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut patches = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|e| e.to_str()) else { continue };
+            let Some(num) = name.strip_prefix(consts::RES_PATCH_PREFIX).and_then(|e| e.strip_suffix(consts::RES_PATCH_SUFFIX))
+                else { continue };
+            let num = num.parse::<u64>().ok();
+            patches.push(((num.is_none(), num, name.to_string()), path));
+        }
+        patches.sort();
+        let mut patches = patches.into_iter().map(|e| e.1).collect();
+
+        let mut ret = vec![dir.join(consts::RES_PATH)];
+        ret.append(&mut patches);
+        Ok(ret)
+    }
+
+    pub fn load(&mut self, path: PathBuf, backend: Backend) -> Result<()> {
+        let file = std::fs::File::open(&path)?;
+        if backend == Backend::Mmap {
+            match Self::map(&file) {
+                Ok(map) => {
+                    let resource = Resource::read(&mut Cursor::new(&map[..]))?;
+                    self.push(path, resource);
+                    self.packs.last_mut().unwrap().map = Some(map);
+                    return Ok(());
+                }
+                Err(e) => warn!("Reading {:?} instead of mapping it: {}", path, e),
+            }
+        }
+
+        let resource = Resource::read(&mut BufReader::new(file))?;
+        self.push(path, resource);
+        Ok(())
+    }
+
+    fn map(file: &std::fs::File) -> Result<Mmap> {
+        let len = file.metadata()?.len();
+        if len > consts::MMAP_MAX_SIZE {
+            return Err(anyhow!("{} bytes exceed the address space", len));
+        }
+        // SAFETY: packs are never written in place, kpack writes a temp file and renames it.
+        Ok(unsafe { Mmap::map(file)? })
+    }
+
+    pub fn push(&mut self, path: PathBuf, resource: Resource) {
+        let pack = self.packs.len();
+        for name in resource.files.keys() {
+            self.index.insert(name.clone(), pack);
+        }
+        self.packs.push(Pack { path, resource, map: None });
+    }
+
+    /// Returns the 1-based index of the entry, the pack serving it and the entry itself.
+    pub fn get(&self, file: &str) -> Option<(usize, usize, &Pack, &FileEntry)> {
+        let (idx, _, &pack) = self.index.get_full(file)?;
+        let pack_ref = &self.packs[pack];
+        let entry = pack_ref.resource.files.get(file)?;
+        Some((idx + 1, pack, pack_ref, entry))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::resource::FSType;
+
+    use super::*;
+
+    fn resource(files: &[(&str, u64)]) -> Resource {
+        let mut ret = Resource::default();
+        for (name, size) in files {
+            let entry = FileEntry::new(FSType::Unpack, name.to_string(), name.to_string(), 0, *size);
+            ret.files.insert(name.to_string(), entry);
+        }
+        ret
+    }
+
+    #[test]
+    fn test_override() {
+        let mut stack = ResourceStack::default();
+        stack.push(PathBuf::from("resource.bin"), resource(&[("a", 1), ("b", 2)]));
+        stack.push(PathBuf::from("resource_patch_01.bin"), resource(&[("b", 3), ("c", 4)]));
+
+        let (_, pack, _, entry) = stack.get("b").unwrap();
+        assert_eq!((pack, entry.size), (1, 3));
+
+        assert_eq!(stack.get("a").unwrap().1, 0);
+        assert_eq!(stack.get("c").unwrap().1, 1);
+        assert!(stack.get("d").is_none());
+    }
+
+    #[test]
+    fn test_discover() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["resource_patch_10.bin", "resource_patch_hotfix.bin", "resource_patch_2.bin", "resource_patch_01.bin", "other.bin"] {
+            std::fs::write(dir.path().join(name), b"")?;
+        }
+
+        let names: Vec<_> = ResourceStack::discover(dir.path())?.iter()
+            .map(|e| e.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["resource.bin", "resource_patch_01.bin", "resource_patch_2.bin", "resource_patch_10.bin", "resource_patch_hotfix.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("resource.bin");
+        std::fs::write(&path, b"0123456789")?;
+
+        let mut pack = Pack { path: path.clone(), resource: Resource::default(), map: None };
+        assert!(pack.slice(0, 1).is_none());
+
+        pack.map = Some(ResourceStack::map(&std::fs::File::open(&path)?)?);
+        assert_eq!(pack.slice(2, 3).unwrap()?, b"234");
+        assert!(pack.slice(8, 3).unwrap().is_err());
+        assert!(pack.slice(usize::MAX as u64, 2).unwrap().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use binrw::binrw;
+use serde::Deserialize;
+
+use super::helper::{KBuf, KString};
+
+/// One step of post-processing a file extracted to disk.
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub enum UnpackOp {
+    /// Drop this many bytes from the start.
+    #[brw(magic = 0u8)]
+    Skip(u64),
+
+    /// Overwrite the start, e.g. the movie magic replaced while packing.
+    #[brw(magic = 1u8)]
+    Magic(KBuf),
+
+    /// Strip this padding byte from the end, wherever the op is listed.
+    #[brw(magic = 2u8)]
+    TrimEnd(u8),
+}
+
+/// Post-processing of the files whose virtual path ends with `suffix`, stored in the pack
+/// so new file types can be unpacked to disk by rebuilding the pack only.
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+#[derive(Deserialize)]
+#[serde(from = "UnpackConfig")]
+pub struct UnpackRule {
+    pub suffix: KString,
+
+    #[bw(calc = ops.len() as u32)]
+    pub op_cnt: u32,
+
+    /// Applied in order to the decoded file.
+    #[br(count = op_cnt)]
+    pub ops: Vec<UnpackOp>,
+}
+
+impl UnpackRule {
+    /// Only overwrite the start of the file with `magic`.
+    pub fn magic(suffix: &str, magic: &[u8]) -> Self {
+        UnpackRule { suffix: suffix.to_string().into(), ops: vec![UnpackOp::Magic(magic.to_vec().into())] }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        name.ends_with(&self.suffix.data)
+    }
+}
+
+/// `{ suffix = ".mzv", skip = 0, magic = "MZV\u0000", trim_end = 0 }`, the ops run in this order.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnpackConfig {
+    suffix: String,
+
+    #[serde(default)]
+    skip: u64,
+
+    magic: Option<String>,
+
+    trim_end: Option<u8>,
+}
+
+impl From<UnpackConfig> for UnpackRule {
+    fn from(config: UnpackConfig) -> Self {
+        let mut ops = Vec::new();
+        if config.skip > 0 {
+            ops.push(UnpackOp::Skip(config.skip));
+        }
+        if let Some(magic) = config.magic {
+            ops.push(UnpackOp::Magic(magic.into_bytes().into()));
+        }
+        if let Some(pad) = config.trim_end {
+            ops.push(UnpackOp::TrimEnd(pad));
+        }
+
+        UnpackRule { suffix: config.suffix.into(), ops }
+    }
+}
+
+/// Applies the ops of a rule to a file streamed through it chunk by chunk.
+pub struct Unpacker<'a> {
+    ops: &'a [UnpackOp],
+
+    /// Bytes seen by each op so far.
+    pos: Vec<u64>,
+
+    /// Bytes handed out by `process`.
+    written: u64,
+
+    /// Output length up to the last byte which isn't padding.
+    kept: u64,
+}
+
+impl<'a> Unpacker<'a> {
+    /// `rule` of `None` passes everything through.
+    pub fn new(rule: Option<&'a UnpackRule>) -> Self {
+        let ops = rule.map_or(&[][..], |e| &e.ops);
+        Self { ops, pos: vec![0; ops.len()], written: 0, kept: 0 }
+    }
+
+    /// Process the next chunk in place, returns the part to be written.
+    pub fn process<'b>(&mut self, mut chunk: &'b mut [u8]) -> &'b mut [u8] {
+        for (op, pos) in self.ops.iter().zip(self.pos.iter_mut()) {
+            let len = chunk.len() as u64;
+            match op {
+                UnpackOp::Skip(n) => {
+                    let drop = n.saturating_sub(*pos).min(len) as usize;
+                    chunk = &mut chunk[drop..];
+                }
+                UnpackOp::Magic(magic) => {
+                    for i in *pos..(magic.data.len() as u64).min(*pos + len) {
+                        chunk[(i - *pos) as usize] = magic.data[i as usize];
+                    }
+                }
+                UnpackOp::TrimEnd(_) => {}
+            }
+            *pos += len;
+        }
+
+        if let Some(i) = chunk.iter().rposition(|e| !self.is_padding(*e)) {
+            self.kept = self.written + i as u64 + 1;
+        }
+        self.written += chunk.len() as u64;
+        chunk
+    }
+
+    fn is_padding(&self, byte: u8) -> bool {
+        self.ops.iter().any(|e| matches!(e, UnpackOp::TrimEnd(pad) if *pad == byte))
+    }
+
+    /// Final length of the file, the trailing padding written so far is to be truncated.
+    pub fn final_len(&self) -> u64 {
+        self.kept
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unpack(rule: Option<&UnpackRule>, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut unpacker = Unpacker::new(rule);
+        let mut ret = Vec::new();
+        for e in data.to_vec().chunks_mut(chunk) {
+            ret.extend_from_slice(unpacker.process(e));
+        }
+        ret.truncate(unpacker.final_len() as usize);
+        ret
+    }
+
+    #[test]
+    fn test_unpack() {
+        let rule: UnpackRule = toml::from_str(r#"suffix = ".ogg"
+            skip = 2
+            magic = "OggS"
+            trim_end = 0"#).unwrap();
+        assert!(rule.matches("sound/bgm01.ogg"));
+        assert!(!rule.matches("sound/bgm01.ogg.m"));
+
+        let data = b"__abcdefgh\0\0\0";
+        for chunk in [1, 3, data.len()] {
+            assert_eq!(unpack(Some(&rule), data, chunk), b"OggSefgh");
+            assert_eq!(unpack(None, data, chunk), data);
+        }
+    }
+}
@@ -0,0 +1,809 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use binrw::BinWrite;
+use indexmap::IndexSet;
+use log::{debug, error, info};
+use md5::{Digest, Md5};
+use relative_path::PathExt;
+use tempfile::TempDir;
+use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+
+use crate::data::resource::{Compression, FileEntry};
+use crate::data::stack::{Backend, Pack, ResourceStack};
+use crate::data::unpack::Unpacker;
+use crate::ffi::{self, MappingInfo, RetCode};
+use crate::utils::cipher::{Cipher, CipherReader};
+use crate::utils::consts;
+use crate::utils::trace::Trace;
+
+/// Set in `MappingInfo::idx` of loose files, below the `UID_MARK` of the launcher.
+const LOOSE_MARK: u32 = 0x4000_0000;
+
+/// Compressed entries kept inflated, the game interleaves the chunked reads of a few files.
+const INFLATED_SLOTS: usize = 4;
+
+/// Plain data of the compressed entry stored at `offset` of `pack`.
+struct Inflated {
+    pack: u32,
+    offset: u64,
+    plain: Arc<Vec<u8>>,
+}
+
+/// The loaded packs and the folder movies are decrypted into, everything the hooks need at runtime.
+pub struct ResourceHandle {
+    stack: ResourceStack,
+
+    /// Removed together with the decrypted movies on `close`.
+    unpack_dir: Option<TempDir>,
+
+    /// Files in here are served as is instead of from the packs, e.g. `patch/motion/foo.psb.m`.
+    override_dir: Option<PathBuf>,
+
+    /// Virtual paths handed out so far, the 1-based idx of their `MappingInfo` points in here.
+    /// Kept by `replace_stack`, so files the game opened before a reload keep reading the same path.
+    ids: Mutex<IndexSet<String>>,
+
+    /// Loose files handed out so far, the idx of their `MappingInfo` points in here.
+    loose: Mutex<IndexSet<PathBuf>>,
+
+    /// Pack index => opened pack, shared by all reads of packs which aren't mapped.
+    files: Mutex<HashMap<u32, File>>,
+
+    /// The compressed entries read last, most recent first.
+    inflated: Mutex<VecDeque<Inflated>>,
+
+    /// Compressed entries inflated so far.
+    inflations: AtomicUsize,
+
+    /// Every lookup, when tracing is on.
+    trace: Option<Mutex<Trace>>,
+}
+
+impl ResourceHandle {
+    /// Load resource.bin and the patch packs on top of it from `dir`.
+    pub fn open(dir: &Path, backend: Backend) -> Result<Self, RetCode> {
+        Ok(Self {
+            stack: Self::load_stack(dir, backend)?,
+            unpack_dir: None,
+            override_dir: None,
+            ids: Mutex::new(IndexSet::new()),
+            loose: Mutex::new(IndexSet::new()),
+            files: Mutex::new(HashMap::new()),
+            inflated: Mutex::new(VecDeque::new()),
+            inflations: AtomicUsize::new(0),
+            trace: None,
+        })
+    }
+
+    /// Parse the packs in `dir` without touching any handle, see `replace_stack`.
+    pub fn load_stack(dir: &Path, backend: Backend) -> Result<ResourceStack, RetCode> {
+        let Ok(packs) = ResourceStack::discover(dir)
+            else {
+                error!("Failed to list resource files in {:?}", dir);
+                return Err(RetCode::ResourceFileNotFound);
+            };
+
+        let mut stack = ResourceStack::default();
+        for (i, path) in packs.into_iter().enumerate() {
+            if !path.exists() {
+                error!("Failed to open resource file {:?}", path);
+                return Err(RetCode::ResourceFileNotFound);
+            }
+
+            match stack.load(path.clone(), backend) {
+                Ok(_) => info!("Loaded pack {i}: {:?}", path),
+                // A broken hotfix pack must not take the base pack down with it.
+                Err(e) if i > 0 => error!("Failed to parse patch pack {:?}, skipped: {e}", path),
+                Err(_) => {
+                    error!("Failed to parse resource file.");
+                    return Err(RetCode::ParseResourceFailed);
+                }
+            }
+        }
+
+        Ok(stack)
+    }
+
+    /// The packs in `dir` with their modification times, changes whenever a pack is rebuilt, added or removed.
+    pub fn stamp(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let packs = ResourceStack::discover(dir).unwrap_or_default();
+        packs.into_iter()
+            .map(|e| {
+                let modified = std::fs::metadata(&e).and_then(|m| m.modified()).ok();
+                (e, modified)
+            })
+            .collect()
+    }
+
+    /// Serve lookups from `stack` from now on, dropping the movies whose content changed with it.
+    pub fn replace_stack(&mut self, stack: ResourceStack) {
+        self.stack = stack;
+        self.files.get_mut().map(|e| e.clear()).ok();
+        self.inflated.get_mut().map(|e| e.clear()).ok();
+
+        let Some(dir) = &self.unpack_dir else { return };
+        let mut movies = std::fs::read_dir(dir.path()).into_iter().flatten().flatten().peekable();
+        if movies.peek().is_none() {
+            return;
+        }
+
+        let current: HashSet<String> = self.stack.index.keys()
+            .filter_map(|name| self.stack.get(name).map(|(_, _, pack, entry)| movie_stem(name, pack, entry)))
+            .collect();
+        for entry in movies {
+            let path = entry.path();
+            let Some(stem) = path.file_stem().and_then(|e| e.to_str()) else { continue };
+            // Might still be playing, it is decrypted again once closed.
+            if !stem.starts_with(".tmp") && !current.contains(stem) && std::fs::remove_file(&path).is_ok() {
+                debug!("Evicted changed movie: {:?}", path);
+            }
+        }
+    }
+
+    /// Decrypt movies into `dir` from now on.
+    pub fn set_unpack_dir(&mut self, dir: TempDir) {
+        self.unpack_dir = Some(dir);
+    }
+
+    /// Serve files found in `dir` instead of the packed ones, an empty `dir` turns this off.
+    pub fn set_override_dir(&mut self, dir: &str) {
+        self.override_dir = if dir.is_empty() { None } else { Some(PathBuf::from(dir)) };
+    }
+
+    /// Record every `lookup` from now on, see `take_trace`.
+    pub fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Default::default);
+    }
+
+    /// The lookups recorded so far, tracing stays on with an empty trace.
+    pub fn take_trace(&self) -> Option<Trace> {
+        let mut trace = self.trace.as_ref()?.lock().ok()?;
+        Some(std::mem::take(&mut *trace))
+    }
+
+    fn record(&self, file: &str, pack: Option<&str>) {
+        if let Some(mut trace) = self.trace.as_ref().and_then(|e| e.lock().ok()) {
+            trace.record(file, pack);
+        }
+    }
+
+    /// Unload the packs and remove the unpack dir, lookups fail afterwards.
+    pub fn close(&mut self) {
+        self.stack = ResourceStack::default();
+        self.files.get_mut().map(|e| e.clear()).ok();
+        self.inflated.get_mut().map(|e| e.clear()).ok();
+        drop(self.unpack_dir.take());
+    }
+
+    pub fn stack(&self) -> &ResourceStack {
+        &self.stack
+    }
+
+    pub fn lookup(&self, file: &str) -> Result<MappingInfo> {
+        self.resolve(file, true)
+    }
+
+    /// Same as `lookup` without recording it, for queries the game didn't make to open the file.
+    pub fn peek(&self, file: &str) -> Result<MappingInfo> {
+        self.resolve(file, false)
+    }
+
+    fn resolve(&self, file: &str, trace: bool) -> Result<MappingInfo> {
+        let record = |pack: Option<&str>| if trace { self.record(file, pack) };
+
+        if let Some(path) = self.override_dir.as_ref().map(|e| e.join(file)).filter(|e| e.is_file()) {
+            let (idx, _) = self.loose.lock().map_err(|_| anyhow!("Loose table poisoned"))?.insert_full(path.clone());
+            if idx as u32 >= LOOSE_MARK {
+                return Err(anyhow!("Too many loose files"));
+            }
+
+            debug!("Loose file: {file} -> {:?}", path);
+            record(Some("loose"));
+            return build_loose_info(LOOSE_MARK | idx as u32, &path);
+        }
+
+        let Some((_, pack, p, v)) = self.stack.get(file) else {
+            record(None);
+            return Err(anyhow!("Req file not found: {file}"));
+        };
+        record(p.path.file_name().and_then(|e| e.to_str()));
+
+        let (idx, _) = self.ids.lock().map_err(|_| anyhow!("Id table poisoned"))?.insert_full(file.to_string());
+        if idx as u32 + 1 >= LOOSE_MARK {
+            return Err(anyhow!("Too many files"));
+        }
+        Ok(build_mapping_info(idx + 1, pack, p, v))
+    }
+
+    pub fn lookup_by_idx(&self, idx: i64) -> Result<MappingInfo> {
+        if idx as u32 & LOOSE_MARK != 0 {
+            return build_loose_info(idx as u32, Path::new(&self.loose_file(idx as u32)?));
+        }
+
+        let name = usize::try_from(idx).ok()
+            .and_then(|e| self.ids.lock().ok()?.get_index(e.checked_sub(1)?).cloned())
+            .ok_or_else(|| anyhow!("idx {idx} out of range"))?;
+        let (_, pack, p, v) = self.stack.get(&name).ok_or_else(|| anyhow!("File {name} gone since reload"))?;
+
+        let ret = build_mapping_info(idx as usize, pack, p, v);
+
+        debug!("From idx {idx} get file: {name}, {:?}", ret);
+
+        Ok(ret)
+    }
+
+    fn pack(&self, pack: u32) -> Result<&Pack> {
+        self.stack.packs.get(pack as usize).ok_or_else(|| anyhow!("pack {pack} out of range"))
+    }
+
+    pub fn pack_file(&self, pack: u32) -> Result<String> {
+        Ok(self.pack(pack)?.path.to_str().unwrap().to_string())
+    }
+
+    /// Path of the loose file behind `idx` of a `MappingInfo` with `loose` set.
+    pub fn loose_file(&self, idx: u32) -> Result<String> {
+        let loose = self.loose.lock().map_err(|_| anyhow!("Loose table poisoned"))?;
+        let path = loose.get_index((idx & !LOOSE_MARK) as usize).ok_or_else(|| anyhow!("Loose idx {idx} out of range"))?;
+        Ok(path.to_str().unwrap().to_string())
+    }
+
+    pub fn unpack_dir(&self) -> Result<&Path> {
+        self.unpack_dir.as_ref().map(|e| e.path()).ok_or_else(|| anyhow!("No unpack dir"))
+    }
+
+    pub fn decrypt(&self, buf: &mut [u8], info: &MappingInfo) -> Result<()> {
+        self.pack(info.pack)?.resource.entry_cipher(info.uid)?.apply(buf, 0);
+        Ok(())
+    }
+
+    /// Decrypt the `stored_size` bytes in `src` and decompress them into `dst` of `size` bytes.
+    pub fn decode(&self, src: &mut [u8], dst: &mut [u8], info: &MappingInfo) -> Result<()> {
+        self.decrypt(src, info)?;
+
+        let compression = if info.compressed { Compression::Deflate } else { Compression::None };
+        compression.decompress(src, dst)?;
+        Ok(())
+    }
+
+    /// Read the plain bytes of the entry behind `idx` starting at `offset` inside it.
+    /// Returns the number of bytes read, less than `buf.len()` at the end of the entry.
+    pub fn read_entry(&self, idx: u32, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let info = self.lookup_by_idx(idx as i64)?;
+
+        let len = info.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..len];
+        if len == 0 {
+            return Ok(0);
+        }
+
+        if info.loose {
+            let mut file = File::open(self.loose_file(info.idx)?)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(buf)?;
+        } else if info.compressed {
+            let plain = self.inflate(&info)?;
+            let start = offset as usize;
+            buf.copy_from_slice(&plain[start..start + len]);
+        } else {
+            self.read_pack(info.pack, info.offset + offset, buf)?;
+            self.pack(info.pack)?.resource.entry_cipher(info.uid)?.apply(buf, offset);
+        }
+
+        Ok(len)
+    }
+
+    /// Deflate streams can't be entered in the middle, inflate the whole entry once for all its chunks.
+    fn inflate(&self, info: &MappingInfo) -> Result<Arc<Vec<u8>>> {
+        let lock = || self.inflated.lock().map_err(|_| anyhow!("Inflated entries poisoned"));
+        let key = (info.pack, info.offset);
+        {
+            let mut inflated = lock()?;
+            if let Some(i) = inflated.iter().position(|e| (e.pack, e.offset) == key) {
+                let e = inflated.remove(i).unwrap();
+                let plain = e.plain.clone();
+                inflated.push_front(e);
+                return Ok(plain);
+            }
+        }
+
+        let mut stored = vec![0u8; usize::try_from(info.stored_size)?];
+        self.read_pack(info.pack, info.offset, &mut stored)?;
+        let mut plain = vec![0u8; usize::try_from(info.size)?];
+        self.decode(&mut stored, &mut plain, info)?;
+
+        let plain = Arc::new(plain);
+        self.inflations.fetch_add(1, Ordering::Relaxed);
+
+        // Another thread may have inflated it meanwhile.
+        let mut inflated = lock()?;
+        inflated.retain(|e| (e.pack, e.offset) != key);
+        inflated.push_front(Inflated { pack: info.pack, offset: info.offset, plain: plain.clone() });
+        inflated.truncate(INFLATED_SLOTS);
+        Ok(plain)
+    }
+
+    fn read_pack(&self, pack: u32, pos: u64, buf: &mut [u8]) -> Result<()> {
+        if let Some(src) = self.pack(pack)?.slice(pos, buf.len()) {
+            buf.copy_from_slice(src?);
+            return Ok(());
+        }
+
+        let mut files = self.files.lock().map_err(|_| anyhow!("Pack files poisoned"))?;
+        let file = match files.entry(pack) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => e.insert(File::open(&self.pack(pack)?.path)?),
+        };
+
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Decrypt the movie into the unpack dir, returns its path relative to the parent of the unpack dir.
+    ///
+    /// Cached movies are named after the content hash of the entry and its unpack rule,
+    /// so a rebuilt pack never serves a stale one.
+    pub fn locate_movie(&self, filename: &str) -> Result<String> {
+        let tmp = self.unpack_dir()?;
+
+        let (_, pack_idx, pack, entry) = self.stack.get(filename).ok_or_else(|| anyhow!("Movie not found: {filename}"))?;
+
+        let ext = Path::new(filename).extension().and_then(|e| e.to_str()).unwrap_or("bin");
+        let file = tmp.join(format!("{}.{ext}", movie_stem(filename, pack, entry)));
+        let prefix = movie_prefix(filename);
+
+        if file.exists() {
+            debug!("Using cached file: {:?}", file);
+            // Keeps it away from eviction, fails harmlessly while the movie is playing.
+            File::options().write(true).open(&file).and_then(|f| f.set_modified(SystemTime::now())).ok();
+        } else {
+            self.extract_movie(pack_idx as u32, pack, filename, entry, &file)?;
+            evict_movies(tmp, &prefix, &file);
+        }
+
+        let base = tmp.parent().unwrap().to_path_buf();
+
+        let rel = file.relative_to(base)?.to_string();
+        debug!("Locate movie: {:?} -> {:?} rel: {:?}", filename, file, rel);
+        Ok(rel)
+    }
+
+    /// Stream the decoded entry into `file` chunk by chunk, post-processed by the unpack rule of the pack.
+    fn extract_movie(&self, pack_idx: u32, pack: &Pack, filename: &str, entry: &FileEntry, file: &Path) -> Result<()> {
+        let stored = StoredReader {
+            handle: self,
+            pack: pack_idx,
+            pos: pack.resource.end_of_header + entry.real_offset,
+            remaining: entry.stored_size,
+        };
+        let mut reader = entry.compression.decoder(CipherReader::new(stored, pack.resource.entry_cipher(entry.blob_uid)?));
+
+        // Renamed once complete, a crash or a full disk never leaves a truncated movie behind.
+        let dir = file.parent().ok_or_else(|| anyhow!("Invalid movie path: {:?}", file))?;
+        let out = tempfile::Builder::new().make_in(dir, |path| {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .attributes(FILE_ATTRIBUTE_HIDDEN)
+                .open(path)
+        })?;
+
+        let mut unpacker = Unpacker::new(pack.resource.rules.iter().find(|e| e.matches(filename)));
+
+        let mut bw = BufWriter::new(out);
+        let mut buf = vec![0u8; consts::PACK_CHUNK_SIZE];
+        let mut decoded = 0usize;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            bw.write_all(unpacker.process(&mut buf[..n]))?;
+            decoded += n;
+        }
+
+        if decoded as u64 != entry.size {
+            return Err(anyhow!("Movie {:?} is truncated: {decoded} of {} bytes", file, entry.size));
+        }
+
+        let out = bw.into_inner().map_err(|e| e.into_error())?;
+        out.as_file().set_len(unpacker.final_len())?;
+        out.persist(file)?;
+        Ok(())
+    }
+}
+
+/// Create the folder of `tmp` a handle decrypts movies into, e.g. `windata/.ac_movie_scX3kq9Z`.
+///
+/// The random suffix gives every handle its own folder, dropping the handle a reload replaces
+/// would delete the folder of the new one otherwise.
+pub fn create_unpack_dir(tmp: &Path) -> Result<TempDir> {
+    let (Some(parent), Some(name)) = (tmp.parent(), tmp.file_name())
+        else { return Err(anyhow!("Invalid unpack dir: {:?}", tmp)); };
+
+    Ok(tempfile::Builder::new().prefix(name).rand_bytes(6).tempdir_in(parent)?)
+}
+
+/// Remove the folders of `tmp` left behind by a crash, all but `keep`.
+pub fn remove_unpack_dirs(tmp: &Path, keep: Option<&Path>) {
+    let (Some(parent), Some(name)) = (tmp.parent(), tmp.file_name().and_then(|e| e.to_str()))
+        else { return; };
+
+    for entry in std::fs::read_dir(parent).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let old = path.file_name().and_then(|e| e.to_str()).is_some_and(|e| e.starts_with(name));
+        if old && path.is_dir() && Some(path.as_path()) != keep {
+            debug!("Remove old {:?} folder", path);
+            std::fs::remove_dir_all(&path).ok();
+        }
+    }
+}
+
+/// Cached movies of `filename` start with this, whatever their content.
+fn movie_prefix(filename: &str) -> String {
+    format!("{:x}", Md5::digest(filename))
+}
+
+/// Name of the cached movie without extension, changes with the content of the entry
+/// and with the unpack rule of `pack` applied to it.
+fn movie_stem(filename: &str, pack: &Pack, entry: &FileEntry) -> String {
+    let mut hash = Md5::new();
+    hash.update(entry.hash);
+    if let Some(rule) = pack.resource.rules.iter().find(|e| e.matches(filename)) {
+        let mut buf = Cursor::new(Vec::new());
+        rule.write(&mut buf).ok();
+        hash.update(buf.into_inner());
+    }
+    format!("{}_{:x}", movie_prefix(filename), hash.finalize())
+}
+
+/// Remove older versions of the movie `prefix` and the least recently played movies beyond
+/// `MOVIE_CACHE_SIZE`, `keep` survives either way. Movies still playing fail to be removed and are skipped.
+fn evict_movies(dir: &Path, prefix: &str, keep: &Path) {
+    let mut movies = Vec::new();
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|e| e.to_str()) else { continue };
+        // Still being extracted.
+        if path == keep || name.starts_with(".tmp") {
+            continue;
+        }
+
+        let stale = name.starts_with(&format!("{prefix}_"));
+        if stale && std::fs::remove_file(&path).is_ok() {
+            debug!("Evicted stale movie: {:?}", path);
+            continue;
+        }
+
+        if let Ok(meta) = entry.metadata() {
+            movies.push((meta.modified().ok(), meta.len(), path));
+        }
+    }
+
+    let mut total: u64 = movies.iter().map(|e| e.1).sum::<u64>()
+        + std::fs::metadata(keep).map(|m| m.len()).unwrap_or(0);
+    movies.sort();
+    for (_, size, path) in movies {
+        if total <= consts::MOVIE_CACHE_SIZE {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            debug!("Evicted movie: {:?}", path);
+            total -= size;
+        }
+    }
+}
+
+/// The stored bytes of an entry, read through the handle so mapped packs are sliced instead.
+struct StoredReader<'a> {
+    handle: &'a ResourceHandle,
+    pack: u32,
+    pos: u64,
+    remaining: u64,
+}
+
+impl Read for StoredReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (buf.len() as u64).min(self.remaining) as usize;
+        self.handle.read_pack(self.pack, self.pos, &mut buf[..n]).map_err(std::io::Error::other)?;
+        self.pos += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn build_mapping_info(idx: usize, pack: usize, p: &Pack, v: &FileEntry) -> MappingInfo {
+    MappingInfo {
+        idx: idx as u32,
+        pack: pack as u32,
+        uid: v.blob_uid,
+        offset: p.resource.end_of_header + v.real_offset,
+        size: v.size,
+        stored_size: v.stored_size,
+        compressed: v.compression != Compression::None,
+        loose: false,
+    }
+}
+
+fn build_loose_info(idx: u32, path: &Path) -> Result<MappingInfo> {
+    let size = std::fs::metadata(path)?.len();
+    Ok(MappingInfo {
+        idx,
+        pack: 0,
+        uid: 0,
+        offset: 0,
+        size,
+        stored_size: size,
+        compressed: false,
+        loose: true,
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::data::resource::{FileEntry, Resource};
+    use crate::data::unpack::UnpackRule;
+    use crate::utils::manifest;
+
+    use super::*;
+
+    /// Write `files` of (virtual path, content, compression) into `dir/resource.bin`.
+    fn write_pack(dir: &Path, files: &[(&str, &[u8], Compression)]) -> Result<()> {
+        write_pack_with(dir, files, manifest::default_unpack())
+    }
+
+    fn write_pack_with(dir: &Path, files: &[(&str, &[u8], Compression)], rules: Vec<UnpackRule>) -> Result<()> {
+        let mut resource = Resource {
+            key: "test".to_string(),
+            rules,
+            ..Default::default()
+        };
+        for (i, (name, data, compression)) in files.iter().enumerate() {
+            let src = dir.join(format!("src_{i}"));
+            std::fs::write(&src, data)?;
+
+            let mut entry = FileEntry::standalone(name, src, data.len() as u64);
+            entry.compression = *compression;
+            resource.insert_file(name.to_string(), entry)?;
+        }
+        resource.scan_sources()?;
+        resource.calc_offsets()?;
+        resource.write(&mut BufWriter::new(File::create(dir.join(consts::RES_PATH))?))?;
+        Ok(())
+    }
+
+    fn open(dir: &Path, backend: Backend) -> Result<ResourceHandle> {
+        ResourceHandle::open(dir, backend).map_err(|e| anyhow!("open failed: {}", e.repr))
+    }
+
+    #[test]
+    fn test_handle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_pack(dir.path(), &[("script/a.txt.scn.m", b"translated", Compression::None)])?;
+
+        for backend in [Backend::File, Backend::Mmap] {
+            let mut handle = open(dir.path(), backend)?;
+            let info = handle.lookup("script/a.txt.scn.m")?;
+            assert_eq!(handle.lookup_by_idx(info.idx as i64)?.offset, info.offset);
+
+            let data = std::fs::read(handle.pack_file(info.pack)?)?;
+            let mut buf = data[info.offset as usize..(info.offset + info.stored_size) as usize].to_vec();
+            handle.decrypt(&mut buf, &info)?;
+            assert_eq!(buf, b"translated");
+
+            let mut buf = [0u8; 8];
+            assert_eq!(handle.read_entry(info.idx, 5, &mut buf)?, 5);
+            assert_eq!(&buf[..5], b"lated");
+            assert_eq!(handle.read_entry(info.idx, 10, &mut buf)?, 0);
+
+            handle.close();
+            assert!(handle.lookup("script/a.txt.scn.m").is_err());
+            assert!(handle.unpack_dir().is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_compressed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let plain = b"compressed ".repeat(100);
+        let other = b"other ".repeat(100);
+        // Distinct contents, a shared blob would be inflated once for all of them.
+        let others: Vec<_> = (0..INFLATED_SLOTS)
+            .map(|i| (format!("script/c{i}.txt.scn.m"), format!("more {i} ").repeat(100).into_bytes()))
+            .collect();
+        let mut files = vec![
+            ("script/a.txt.scn.m", plain.as_slice(), Compression::Deflate),
+            ("script/b.txt.scn.m", other.as_slice(), Compression::Deflate),
+        ];
+        files.extend(others.iter().map(|(name, data)| (name.as_str(), data.as_slice(), Compression::Deflate)));
+        write_pack(dir.path(), &files)?;
+        let handle = open(dir.path(), Backend::File)?;
+        let a = handle.lookup("script/a.txt.scn.m")?;
+        let b = handle.lookup("script/b.txt.scn.m")?;
+        assert!(a.compressed && b.compressed);
+
+        let mut read = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = handle.read_entry(a.idx, read.len() as u64, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+            assert_eq!(handle.read_entry(b.idx, 1, &mut buf[..3])?, 3);
+        }
+        assert_eq!(read, plain);
+        assert_eq!(handle.read_entry(a.idx, a.size + 10, &mut buf)?, 0);
+        // Interleaved chunks of two entries, each inflated once.
+        assert_eq!(handle.inflations.load(Ordering::Relaxed), 2);
+
+        // Pushed out by the least recently read.
+        for (name, _) in others.iter() {
+            let info = handle.lookup(name)?;
+            assert!(info.compressed);
+            assert_eq!(handle.read_entry(info.idx, 0, &mut buf)?, buf.len());
+        }
+        assert_eq!(handle.inflations.load(Ordering::Relaxed), 2 + INFLATED_SLOTS);
+        handle.read_entry(a.idx, 0, &mut buf)?;
+        assert_eq!(handle.inflations.load(Ordering::Relaxed), 3 + INFLATED_SLOTS);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_pack(dir.path(), &[
+            ("script/a.txt.scn.m", b"v1", Compression::None),
+            ("script/b.txt.scn.m", b"b", Compression::None),
+            ("movies/op.mzv", b"ABCDop", Compression::None),
+            ("movies/ed.mzv", b"ABCDed", Compression::None),
+        ])?;
+        let mut handle = open(dir.path(), Backend::File)?;
+        std::fs::create_dir(dir.path().join("windata"))?;
+        handle.set_unpack_dir(create_unpack_dir(&dir.path().join("windata/.ac_movie_sc"))?);
+        let stamp = ResourceHandle::stamp(dir.path());
+        let a = handle.lookup("script/a.txt.scn.m")?;
+        assert_eq!(a.size, 2);
+        let op = dir.path().join("windata").join(handle.locate_movie("movies/op.mzv")?);
+        let ed = dir.path().join("windata").join(handle.locate_movie("movies/ed.mzv")?);
+
+        std::fs::write(dir.path().join("resource_patch_01.bin"), b"broken")?;
+        assert_ne!(ResourceHandle::stamp(dir.path()), stamp);
+
+        // Reordered, a is no longer the first entry of the pack.
+        write_pack(dir.path(), &[
+            ("script/b.txt.scn.m", b"b", Compression::None),
+            ("script/a.txt.scn.m", b"v2, longer", Compression::None),
+            ("movies/op.mzv", b"ABCDop", Compression::None),
+            ("movies/ed.mzv", b"ABCDed, new cut", Compression::None),
+        ])?;
+        let stack = ResourceHandle::load_stack(dir.path(), Backend::File).map_err(|e| anyhow!("reload failed: {}", e.repr))?;
+        handle.replace_stack(stack);
+        assert!(op.exists());
+        assert!(!ed.exists());
+
+        // Files opened before the reload read the new version of the same path.
+        let mut buf = [0u8; 16];
+        assert_eq!(handle.read_entry(a.idx, 0, &mut buf)?, 10);
+        assert_eq!(&buf[..10], b"v2, longer");
+        assert_eq!(handle.lookup("script/a.txt.scn.m")?.idx, a.idx);
+        assert_ne!(handle.lookup("script/b.txt.scn.m")?.idx, a.idx);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_rules() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files: &[(&str, &[u8], Compression)] = &[("movies/op.mzv", b"ABCDop", Compression::None)];
+        write_pack(dir.path(), files)?;
+        let mut handle = open(dir.path(), Backend::File)?;
+        std::fs::create_dir(dir.path().join("windata"))?;
+        handle.set_unpack_dir(create_unpack_dir(&dir.path().join("windata/.ac_movie_sc"))?);
+        let old = dir.path().join("windata").join(handle.locate_movie("movies/op.mzv")?);
+
+        // Same content, unpacked with another magic.
+        write_pack_with(dir.path(), files, vec![UnpackRule::magic(".mzv", b"MZV1")])?;
+        let stack = ResourceHandle::load_stack(dir.path(), Backend::File).map_err(|e| anyhow!("reload failed: {}", e.repr))?;
+        handle.replace_stack(stack);
+        assert!(!old.exists());
+
+        let rel = handle.locate_movie("movies/op.mzv")?;
+        assert_eq!(std::fs::read(dir.path().join("windata").join(rel))?, b"MZV1op");
+        Ok(())
+    }
+
+    #[test]
+    fn test_override_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_pack(dir.path(), &[
+            ("script/a.txt.scn.m", b"packed", Compression::None),
+            ("script/b.txt.scn.m", b"packed", Compression::None),
+        ])?;
+        std::fs::create_dir_all(dir.path().join("patch/script"))?;
+        std::fs::write(dir.path().join("patch/script/b.txt.scn.m"), b"edited")?;
+
+        let mut handle = open(dir.path(), Backend::File)?;
+        handle.enable_trace();
+        assert!(!handle.lookup("script/b.txt.scn.m")?.loose);
+
+        handle.set_override_dir(dir.path().join("patch").to_str().unwrap());
+        assert!(!handle.lookup("script/a.txt.scn.m")?.loose);
+
+        let info = handle.lookup("script/b.txt.scn.m")?;
+        assert!(info.loose);
+        assert_eq!((info.offset, info.size), (0, 6));
+        assert_eq!(handle.lookup_by_idx(info.idx as i64)?.idx, info.idx);
+        assert_eq!(std::fs::read(handle.loose_file(info.idx)?)?, b"edited");
+
+        assert!(handle.lookup("script/c.txt.scn.m").is_err());
+        assert_eq!(handle.peek("script/b.txt.scn.m")?.size, 6);
+        assert!(handle.peek("windata/other.bin").is_err());
+        let trace = handle.take_trace().unwrap();
+        assert_eq!(trace.files["script/a.txt.scn.m"].pack.as_deref(), Some(consts::RES_PATH));
+        assert_eq!(trace.files["script/b.txt.scn.m"].pack.as_deref(), Some("loose"));
+        assert_eq!(trace.files["script/b.txt.scn.m"].count, 2);
+        assert_eq!(trace.files["script/c.txt.scn.m"].pack, None);
+        assert_eq!(trace.files.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_movie() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let plain = [b"ABCD".as_slice(), &b"movie ".repeat(64)].concat();
+        write_pack(dir.path(), &[
+            ("movies/op.mzv", &plain, Compression::None),
+            ("movies/ed.mzv", &plain, Compression::Deflate),
+        ])?;
+
+        let mut handle = open(dir.path(), Backend::File)?;
+        std::fs::create_dir(dir.path().join("windata"))?;
+        let unpack = create_unpack_dir(&dir.path().join("windata/.ac_movie_sc"))?;
+        let stale = unpack.path().join(format!("{:x}_old.mzv", Md5::digest("movies/op.mzv")));
+        std::fs::write(&stale, b"stale")?;
+        handle.set_unpack_dir(unpack);
+
+        let expected = [b"MZV\0".as_slice(), &plain[4..]].concat();
+        for name in ["movies/op.mzv", "movies/ed.mzv", "movies/op.mzv"] {
+            let rel = handle.locate_movie(name)?;
+            assert_eq!(std::fs::read(dir.path().join("windata").join(rel))?, expected);
+        }
+        assert!(!stale.exists());
+        assert_eq!(std::fs::read_dir(handle.unpack_dir()?)?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_unpack_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_pack(dir.path(), &[("movies/op.mzv", b"ABCDmovie", Compression::None)])?;
+        let tmp = dir.path().join("windata/.ac_movie_sc");
+        std::fs::create_dir_all(tmp.with_file_name(".ac_movie_sc_crashed"))?;
+
+        let mut res = None;
+        for _ in 0..2 {
+            let keep = res.as_ref().and_then(|e: &ResourceHandle| e.unpack_dir().ok().map(Path::to_path_buf));
+            remove_unpack_dirs(&tmp, keep.as_deref());
+
+            let mut handle = open(dir.path(), Backend::File)?;
+            handle.set_unpack_dir(create_unpack_dir(&tmp)?);
+            res = Some(handle);
+        }
+
+        let rel = res.as_ref().unwrap().locate_movie("movies/op.mzv")?;
+        assert_eq!(std::fs::read(dir.path().join("windata").join(rel))?, b"MZV\0movie");
+        assert_eq!(std::fs::read_dir(dir.path().join("windata"))?.count(), 1);
+        Ok(())
+    }
+}
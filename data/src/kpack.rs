@@ -3,25 +3,30 @@
 #![feature(generic_const_exprs)]
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use binrw::{BinRead, BinWrite};
 use binrw::io::BufReader;
 use bytes::Bytes;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dbg_hex::dbg_hex;
 use derivative::Derivative;
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use regex::Regex;
 
 use data::{mdf, psb};
 use data::context::Context;
 use data::psb::PsbObject;
-use data::resource::{FileEntry, Resource};
+use data::resource::{Compression, FileEntry, Resource};
 use utils::{consts, file_lists::*};
+use utils::cipher::CipherType;
+use utils::manifest::{self, Manifest, Standalone};
+use utils::trace::Trace;
 use crate::data::helper::KString;
 use crate::data::resource::FSType;
 
@@ -30,10 +35,14 @@ pub mod utils;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Key for psb files
-    #[arg(short, long)]
-    key: String,
+    #[arg(short, long, required = true)]
+    key: Option<String>,
 
     /// Key for output file
     #[arg(short, long)]
@@ -49,91 +58,460 @@ struct Args {
     /// Only pack files in this list
     #[arg(short, long)]
     file_lists: Option<PathBuf>,
+
+    /// Previous resource.bin, unchanged entries are copied from it instead of re-encrypted
+    #[arg(short, long)]
+    incremental: Option<PathBuf>,
+
+    /// Directory of loose files mirroring `base/file`, packed instead of the archive entries
+    #[arg(long)]
+    overlay: Option<PathBuf>,
+
+    /// Directory with the original game files, only entries differing from them are packed
+    #[arg(long)]
+    original: Option<PathBuf>,
+
+    /// Deflate entries whose name ends with one of these suffixes
+    #[arg(long, value_delimiter = ',', default_value = ".psb.m,.scn.m")]
+    compress: Vec<String>,
+
+    /// Store every entry without compression
+    #[arg(long)]
+    no_compress: bool,
+
+    /// Cipher for the output file
+    #[arg(long, value_enum, default_value_t = CipherType::Legacy)]
+    cipher: CipherType,
+
+    /// Number of worker threads, 0 for one per core
+    #[arg(short, long, default_value_t = 0)]
+    jobs: usize,
+
+    /// Fail on missing files, unlisted bases and duplicates in the file lists, on missing originals
+    /// and on overlay files replacing no entry
+    #[arg(long)]
+    strict: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Pack as described by a manifest (.toml or .json)
+    Build {
+        manifest: PathBuf,
+    },
+
+    /// List the archive files the game loaded in the traces written by kdata, but which no pack overrides
+    Coverage {
+        manifest: PathBuf,
+
+        /// Traces of kdata, see `trace` in kdata.toml
+        #[arg(required = true)]
+        traces: Vec<PathBuf>,
+    },
+}
+
+/// One unit of work for `process_input`.
+enum Input {
+    /// *_info.psb.m, packing the entries of its body selected by the file list
+    Archive {
+        path: PathBuf,
+        base_name: String,
+        file_list: ListType,
+    },
+
+    /// Packed as is under the virtual path `name`
+    Standalone {
+        path: PathBuf,
+        name: String,
+    },
+}
+
+/// motion_info.psb.m => motion
+fn get_base_name(path: &Path) -> Option<String> {
+    static PAT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.+)_info\.psb\.m$").unwrap());
+
+    let file = path.file_name()?.to_str()?;
+    PAT.captures(file).map(|caps| caps.get(1).unwrap().as_str().to_string())
+}
+
+impl From<Args> for Manifest {
+    fn from(args: Args) -> Self {
+        let (inputs, movies): (Vec<_>, Vec<_>) = args.inputs.into_iter().partition(|e| get_base_name(e).is_some());
+
+        Manifest {
+            key: args.key.unwrap(),
+            encrypt_key: args.encrypt_key,
+            out: args.out,
+            inputs,
+            movies: movies.iter().map(|e| Standalone::parse(e)).collect(),
+            file_lists: args.file_lists,
+            files: FileLists::new(),
+            incremental: args.incremental,
+            overlay: args.overlay,
+            original: args.original,
+            compress: args.compress,
+            no_compress: args.no_compress,
+            cipher: args.cipher,
+            jobs: args.jobs,
+            strict: args.strict,
+            unpack: manifest::default_unpack(),
+        }
+    }
 }
 
 fn main() -> Result<()> {
     env_logger::init();
 
     let args = Args::parse();
+
+    let manifest = match args.command {
+        Some(Command::Build { manifest }) => Manifest::load(&manifest)?,
+        Some(Command::Coverage { manifest, traces }) => return coverage(Manifest::load(&manifest)?, &traces),
+        None => Manifest::from(args),
+    };
+
+    build(manifest)
+}
+
+/// `run` on `manifest.jobs` worker threads.
+fn build(manifest: Manifest) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(manifest.jobs).build()?;
+    pool.install(|| run(manifest))
+}
+
+fn run(args: Manifest) -> Result<()> {
     let key = args.key;
 
+    // Problems of the file lists, the originals and the overlay, fatal in strict mode.
+    let mut problems = Vec::new();
+
     // Parse the file lists
-    let mut file_lists: Option<FileLists> = args.file_lists.map(|file_lists| {
-        let mut file = std::fs::File::open(file_lists).unwrap();
-        serde_json::from_reader(file).unwrap()
-    });
+    let mut file_lists: Option<FileLists> = match args.file_lists {
+        Some(path) => Some(utils::file_lists::load(&path, &mut problems)?),
+        None => None,
+    };
+
+    // Inline lists of the manifest win over the ones from the file.
+    for (base, mut list) in args.files {
+        check_list(&base, &mut list, &mut problems);
+        file_lists.get_or_insert_with(FileLists::new).insert(base, list);
+    }
 
     let encrypt_key = args
         .encrypt_key.unwrap_or_else(|| "[HIDDEN]".to_string());
 
     let mut resource = Resource {
         key: encrypt_key,
+        cipher: args.cipher,
+        rules: args.unpack,
         ..Default::default()
     };
 
-    let pat = Regex::new(r"(.+)_info\.psb\.m$")?;
+    // Hand each input its own file list, so the inputs can be processed independently.
+    let mut inputs = Vec::with_capacity(args.inputs.len() + args.movies.len());
+    for path in args.inputs {
+        let Some(base_name) = get_base_name(&path) else {
+            // Not an archive, keep it under its file name.
+            inputs.push(Input::Standalone { name: Standalone::Path(path.clone()).name(), path });
+            continue;
+        };
+
+        let file_list = match file_lists.as_mut() {
+            Some(file_lists) => file_lists.remove(&base_name).unwrap_or_else(|| {
+                problems.push(format!("Base not in file list: {base_name}"));
+                ListType::None
+            }),
+            None => ListType::None,
+        };
+
+        inputs.push(Input::Archive { path, base_name, file_list });
+    }
+
+    if let Some(file_lists) = &file_lists {
+        let mut unknown: Vec<_> = file_lists.keys().collect();
+        unknown.sort();
+        problems.extend(unknown.into_iter().map(|e| format!("File list for unknown base: {e}")));
+    }
+
+    for movie in args.movies {
+        inputs.push(Input::Standalone { name: movie.name(), path: movie.path().to_path_buf() });
+    }
+
+    // The same inputs of the original game, with everything in them.
+    let mut originals = Vec::new();
+    if let Some(dir) = &args.original {
+        for input in inputs.iter() {
+            let original = match input {
+                Input::Archive { path, base_name, .. } => Input::Archive {
+                    path: dir.join(path.file_name().unwrap()),
+                    base_name: base_name.clone(),
+                    file_list: ListType::All,
+                },
+                Input::Standalone { name, .. } => Input::Standalone { path: dir.join(name), name: name.clone() },
+            };
+            match &original {
+                Input::Archive { path, .. } | Input::Standalone { path, .. } if !path.exists() => {
+                    problems.push(format!("Original missing, packed in full: {:?}", path));
+                }
+                _ => originals.push(original),
+            }
+        }
+    }
+
+    let parts = inputs
+        .into_par_iter()
+        .map(|input| process_input(&key, input))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Merge in the order given on the command line.
+    for (part, part_problems) in parts {
+        resource.merge(part)?;
+        problems.extend(part_problems);
+    }
+
+    if let Some(overlay) = &args.overlay {
+        let summary = resource.apply_overlay(overlay)?;
+        info!("Overlay: {} entries packed from {:?}", summary.applied, overlay);
+        problems.extend(summary.unmatched.into_iter().map(|e| format!("Overlay file replaces no entry: {e}")));
+    }
+
+    for e in problems.iter() {
+        if args.strict { error!("{e}") } else { warn!("{e}") }
+    }
+    if args.strict && !problems.is_empty() {
+        bail!("{} problem(s) in the file lists, originals or overlay, see above", problems.len());
+    }
+
+    if !args.no_compress {
+        for v in resource.files.values_mut() {
+            if args.compress.iter().any(|e| v.name.data.ends_with(e.as_str())) {
+                v.compression = Compression::Deflate;
+            }
+        }
+    }
 
-    for input in args.inputs {
-        // motion.psb.m
-        let file = input.file_name().unwrap().to_str().unwrap();
+    resource.scan_sources()?;
 
-        if let Some(caps) = pat.captures(file) {
-            let base_name = caps.get(1).unwrap().as_str();
+    if args.original.is_some() {
+        let mut original = Resource::default();
+        let parts = originals
+            .into_par_iter()
+            .map(|input| process_input(&key, input))
+            .collect::<Result<Vec<_>>>()?;
+        for (part, _) in parts {
+            original.merge(part)?;
+        }
+
+        // Only the entries we could pack need to be hashed.
+        original.files.retain(|name, _| resource.files.contains_key(name));
+        for name in resource.files.keys().filter(|e| !original.files.contains_key(*e)) {
+            warn!("Not in the original game, packed as new: {name}");
+        }
+        original.scan_sources()?;
+
+        let summary = resource.retain_changed(&original);
+        info!("Diff: {} changed, {} new, {} unchanged and dropped", summary.changed, summary.added, summary.unchanged);
+    }
+
+    let total = resource.calc_offsets()?;
+    let raw: u64 = resource.files.values().map(|v| v.size).sum();
+    info!("Data section size: {total} bytes, uncompressed {raw} bytes");
+    info!("Deduplicated: {} bytes saved", resource.dedup_saved());
+
+    if let Some(prev_path) = args.incremental {
+        let prev = Resource::read(&mut BufReader::new(std::fs::File::open(&prev_path)?))?;
+        let reused = resource.plan_reuse(&prev, prev_path);
+        info!("Incremental: {reused} reused, {} re-encrypted", resource.files.len() - reused);
+    }
+
+    // The previous pack may be the output itself, write to a temp file first.
+    let out_dir = match args.out.parent() {
+        Some(v) if !v.as_os_str().is_empty() => v.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut out = tempfile::NamedTempFile::new_in(out_dir)?;
+
+    {
+        let mut writer = std::io::BufWriter::new(out.as_file_mut());
+        resource.write(&mut writer)?;
+    }
+
+    out.persist(&args.out)?;
+
+    Ok(())
+}
+
+/// Files of one base in the coverage report.
+#[derive(Default)]
+struct BaseCoverage<'a> {
+    files: usize,
+    loaded: usize,
+    /// Loaded from the game files, with the number of lookups
+    untranslated: Vec<(&'a str, u64)>,
+}
+
+fn coverage(manifest: Manifest, traces: &[PathBuf]) -> Result<()> {
+    let mut trace = Trace::default();
+    for path in traces {
+        trace.merge(Trace::load(path)?);
+    }
+
+    // Every file of the archives, regardless of the file lists.
+    let inputs: Vec<_> = manifest.inputs.iter()
+        .filter_map(|path| Some(Input::Archive { path: path.clone(), base_name: get_base_name(path)?, file_list: ListType::All }))
+        .collect();
+    let parts = inputs.into_par_iter()
+        .map(|input| process_input(&manifest.key, input))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut archives = Resource::default();
+    for (part, _) in parts {
+        archives.merge(part)?;
+    }
+
+    let mut bases: BTreeMap<&str, BaseCoverage> = BTreeMap::new();
+    for v in archives.files.values() {
+        bases.entry(&v.base.data).or_default().files += 1;
+    }
+
+    let mut unknown = 0;
+    for (file, e) in &trace.files {
+        let Some(v) = archives.files.get(file) else {
+            unknown += 1;
+            continue;
+        };
+
+        let stat = bases.entry(&v.base.data).or_default();
+        stat.loaded += 1;
+        if e.pack.is_none() {
+            stat.untranslated.push((file, e.count));
+        }
+    }
+
+    for (base, mut stat) in bases {
+        println!("{base}: {} of {} files loaded, {} untranslated", stat.loaded, stat.files, stat.untranslated.len());
+
+        stat.untranslated.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (file, count) in stat.untranslated {
+            println!("    {file} ({count}x)");
+        }
+    }
+
+    if unknown > 0 {
+        println!("{unknown} traced files are in none of the archives");
+    }
+
+    Ok(())
+}
+
+/// Collect the entries of one input into a partial resource, along with the problems of its file list.
+fn process_input(key: &str, input: Input) -> Result<(Resource, Vec<String>)> {
+    let mut resource = Resource::default();
+    let mut problems = Vec::new();
+
+    match input {
+        Input::Archive { path, base_name, mut file_list } => {
+            // motion_info.psb.m
+            let file = path.file_name().unwrap().to_str().unwrap();
             debug!("Processing {file}, base: {base_name}");
 
             // Check psb
             let mut ctx = Context {
-                key: &key,
+                key,
                 mdf_key: Some(format!("{}{}", key, file)),
                 ..Default::default()
             };
 
-            let mut buf = BufReader::new(std::fs::File::open(&input)?);
+            let mut buf = BufReader::new(std::fs::File::open(&path)?);
 
             let mdf = mdf::Mdf::read(&mut buf)?;
             let mut psb = mdf.convert_to_psb(&mut ctx, true)?;
             let mut br = Cursor::new(&mut psb);
             let psb = psb::Psb::read(&mut br)?;
 
-            // let base = input.file_name().unwrap().to_str().unwrap();
-            let mut just_none = ListType::None;
-            let file_list = file_lists
-                .as_mut()
-                .map(|mut file_lists| file_lists.get_mut(base_name).unwrap())
-                .unwrap_or(&mut just_none);
-
-            resource.add_base(base_name.to_string(), input.clone());
-            utils::collect_files(
-                base_name,
+            resource.add_base(base_name.clone(), path.clone());
+            problems = utils::collect_files(
+                &base_name,
                 &psb.entries,
                 &mut resource,
-                file_list,
+                &mut file_list,
             )?;
-        } else {
-            info!("binary file, just add it: {:?}", &input);
-            let bin = std::fs::File::open(&input)?;
-            let size = bin.metadata()?.len();
-            let entry = FileEntry::new(
-                FSType::Unpack,
-                input.to_str().unwrap().to_string(),
-                file.to_string(),
-                0,
-                size as u32,
-            );
-            resource.files.insert(file.to_string(), entry);
+        }
+        Input::Standalone { path, name } => {
+            info!("binary file, just add it: {:?} as {name}", path);
+            let size = std::fs::metadata(&path)?.len();
+            resource.insert_file(name.clone(), FileEntry::standalone(&name, path, size))?;
+        }
+    }
 
+    Ok((resource, problems))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A manifest packing the movies `names` written into `dir`, with `extra` appended.
+    fn manifest(dir: &Path, names: &[&str], extra: &str) -> Result<Manifest> {
+        for name in names {
+            std::fs::write(dir.join(name), format!("MZV\0{name}").repeat(64))?;
         }
+        let movies: Vec<_> = names.iter().map(|e| format!("{e:?}")).collect();
+        let path = dir.join("pack.toml");
+        std::fs::write(&path, format!("key = \"test\"\nmovies = [{}]\n{extra}", movies.join(", ")))?;
+        Manifest::load(&path)
     }
 
-    resource.calc_offsets();
-    // dbg!(&resource);
+    #[test]
+    fn test_cli_cipher() -> Result<()> {
+        let args = Args::try_parse_from(["kpack", "-k", "test", "--cipher", "chacha20"])?;
+        assert_eq!(args.cipher, CipherType::ChaCha20);
+        assert!(Args::try_parse_from(["kpack", "-k", "test", "--cipher", "cha-cha20"]).is_err());
 
-    let mut out = std::fs::File::create(&args.out)?;
-    let mut writer = std::io::BufWriter::new(&mut out);
+        let manifest: Manifest = toml::from_str("key = \"test\"\ncipher = \"chacha20\"")?;
+        assert_eq!(manifest.cipher, CipherType::ChaCha20);
+        Ok(())
+    }
 
-    dbg!(&resource);
+    #[test]
+    fn test_missing_original() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("original"))?;
+        std::fs::write(dir.path().join("original/a.mzv"), "MZV\0a.mzv".repeat(64))?;
 
-    resource.write(&mut writer)?;
+        run(manifest(dir.path(), &["a.mzv", "b.mzv"], "original = \"original\"")?)?;
+        let resource = Resource::read(&mut BufReader::new(std::fs::File::open(dir.path().join("resource.bin"))?))?;
+        assert_eq!(resource.files.keys().collect::<Vec<_>>(), ["b.mzv"]);
 
-    Ok(())
-}
\ No newline at end of file
+        let strict = manifest(dir.path(), &["a.mzv", "b.mzv"], "original = \"original\"\nstrict = true")?;
+        assert!(run(strict).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_unmatched() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("overlay/script"))?;
+        std::fs::write(dir.path().join("overlay/script/typo.txt.scn.m"), b"lost")?;
+
+        run(manifest(dir.path(), &["a.mzv"], "overlay = \"overlay\"")?)?;
+        let strict = manifest(dir.path(), &["a.mzv"], "overlay = \"overlay\"\nstrict = true")?;
+        assert!(run(strict).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_jobs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let names: Vec<_> = (0..16).map(|i| format!("m{i:02}.mzv")).collect();
+        let names: Vec<_> = names.iter().map(String::as_str).collect();
+
+        let mut packs = Vec::new();
+        for jobs in [1, 4] {
+            build(manifest(dir.path(), &names, &format!("out = \"jobs_{jobs}.bin\"\njobs = {jobs}\ncompress = [\"5.mzv\"]"))?)?;
+            packs.push(std::fs::read(dir.path().join(format!("jobs_{jobs}.bin")))?);
+        }
+        assert_eq!(packs[0], packs[1]);
+        Ok(())
+    }
+}
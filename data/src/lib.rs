@@ -8,7 +8,9 @@ use std::ffi::c_char;
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::windows::fs::OpenOptionsExt;
 use std::os::windows::prelude::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -16,21 +18,28 @@ use anyhow::{anyhow, Result};
 use binrw::BinRead;
 use binrw::io::BufReader;
 use hex_literal::hex;
+use log::{debug, error, info, warn};
 use md5::{Digest, Md5};
 use md5::digest::FixedOutput;
+use once_cell::sync::Lazy;
 use nom::HexDisplay;
-use once_cell::sync::OnceCell;
 use relative_path::{PathExt, RelativePath, RelativePathBuf};
 use tempfile::TempDir;
 use windows_sys::Win32::Foundation::{FALSE, GetLastError, SetLastError};
-use windows_sys::Win32::Storage::FileSystem::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM, FILE_ATTRIBUTE_TEMPORARY, SetFileAttributesA};
+use windows_sys::Win32::Storage::FileSystem::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM, FILE_ATTRIBUTE_TEMPORARY, SetFileAttributesW};
 
 
-use crate::data::resource::{FileEntry, FSType, Resource};
-use crate::utils::{consts, generate_xor_key_from_seed, get_entry_key, xor_data};
+use crate::config::Config;
+use crate::data::resource::{Compression, FileEntry, FSType, Resource};
+use crate::data::stack::Backend;
+use crate::handle::ResourceHandle;
+use crate::utils::consts;
 use crate::utils::consts::*;
 
+pub mod config;
 pub mod data;
+pub mod handle;
+pub mod logger;
 pub mod utils;
 
 
@@ -50,9 +59,21 @@ pub mod ffi {
 
     #[derive(Debug, Clone)]
     pub struct MappingInfo {
+        /// 1-based id of the virtual path, used by the hooks to find the entry again. Stable across reloads.
+        pub idx: u32,
+        /// Index of the pack serving this entry, see `get_pack_file`.
+        pub pack: u32,
+        /// Uid of the stored payload, decrypt with this one. Shared by deduplicated entries.
         pub uid: u32,
         pub offset: u64,
+        /// Uncompressed size, report this one to the game.
         pub size: u64,
+        /// Bytes to read from the pack at `offset`.
+        pub stored_size: u64,
+        /// The stored bytes must go through `decode_buffer` instead of `decrypt_buffer`.
+        pub compressed: bool,
+        /// Served as is from `get_loose_file`, neither encrypted nor compressed.
+        pub loose: bool,
     }
 
     extern "Rust" {
@@ -62,12 +83,49 @@ pub mod ffi {
         pub fn release_resource() -> Result<()>;
         pub fn get_mapping_info(file: &str) -> Result<MappingInfo>;
         pub fn get_mapping_info_by_idx(idx: i64) -> Result<MappingInfo>;
+        /// `get_mapping_info` left out of the access trace.
+        pub fn peek_mapping_info(file: &str) -> Result<MappingInfo>;
         pub fn get_resource_dat_file() -> String;
+        pub fn get_pack_file(pack: u32) -> Result<String>;
+        pub fn get_loose_file(idx: u32) -> Result<String>;
+        pub fn set_override_dir(dir: &str) -> Result<()>;
         pub fn decrypt_buffer(buf: &mut [u8], info: &MappingInfo) -> Result<()>;
+        pub fn decode_buffer(src: &mut [u8], dst: &mut [u8], info: &MappingInfo) -> Result<()>;
+        pub fn read_entry(idx: u32, offset: u64, buf: &mut [u8]) -> Result<usize>;
         pub fn get_unpack_dir() -> String;
         pub fn locate_movie(filename: String) -> Result<String>;
 
         pub fn is_debug_mode() -> bool;
+        pub fn get_config() -> RuntimeConfig;
+
+        type ResourceHandle;
+
+        /// Load the packs in `dir`, decrypting movies into `dir/windata`. `mmap` maps the packs instead of reading them.
+        pub fn open_resource(dir: &str, mmap: bool) -> Result<Box<ResourceHandle>>;
+        fn close(self: &mut ResourceHandle);
+        fn lookup(self: &ResourceHandle, file: &str) -> Result<MappingInfo>;
+        fn lookup_by_idx(self: &ResourceHandle, idx: i64) -> Result<MappingInfo>;
+        fn pack_file(self: &ResourceHandle, pack: u32) -> Result<String>;
+        fn loose_file(self: &ResourceHandle, idx: u32) -> Result<String>;
+        fn set_override_dir(self: &mut ResourceHandle, dir: &str);
+        fn decrypt(self: &ResourceHandle, buf: &mut [u8], info: &MappingInfo) -> Result<()>;
+        fn decode(self: &ResourceHandle, src: &mut [u8], dst: &mut [u8], info: &MappingInfo) -> Result<()>;
+        fn read_entry(self: &ResourceHandle, idx: u32, offset: u64, buf: &mut [u8]) -> Result<usize>;
+        fn locate_movie(self: &ResourceHandle, filename: &str) -> Result<String>;
+    }
+
+    /// `Config` for the launcher, paths as strings and `log_level` already resolved.
+    #[derive(Debug, Clone)]
+    pub struct RuntimeConfig {
+        pub resource_dir: String,
+        pub cache_dir: String,
+        pub log_level: String,
+        pub debug: bool,
+        pub mmap: bool,
+        pub hot_reload: bool,
+        pub override_dir: String,
+        /// Empty when tracing is off.
+        pub trace: String,
     }
 
     unsafe extern "C++" {
@@ -84,192 +142,242 @@ pub mod ffi {
 use ffi::RetCode;
 use ffi::MappingInfo;
 
-static RESOURCE: OnceCell<Resource> = OnceCell::new();
-static mut UNPACK_DIR: OnceCell<TempDir> = OnceCell::new();
+/// The handle behind the free functions below.
+static RESOURCE: RwLock<Option<ResourceHandle>> = RwLock::new(None);
+static WATCHING: AtomicBool = AtomicBool::new(false);
 
-/// Load resource dat from current folder.
-pub fn load_resource_dat() -> RetCode {
+/// Read again by every `load_resource_dat`.
+static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| RwLock::new(load_config()));
+
+fn load_config() -> Config {
+    Config::load(Path::new(".")).unwrap_or_else(|e| {
+        // Read before the logger is set up, straight to the launcher.
+        ffi::error(&format!("Failed to load config, using the defaults: {e}"));
+        Config::default()
+    })
+}
+
+fn config() -> Config {
+    CONFIG.read().map(|e| e.clone()).unwrap_or_default()
+}
+
+fn with_resource<T>(f: impl FnOnce(&ResourceHandle) -> Result<T>) -> Result<T> {
+    let res = RESOURCE.read().map_err(|_| anyhow!("Resource lock poisoned"))?;
+    f(res.as_ref().ok_or_else(|| anyhow!("Resource not loaded"))?)
+}
+
+/// Create the hidden folder of `tmp` the movies are decrypted into, e.g. `windata/.ac_movie_scX3kq9Z`.
+fn create_unpack_dir(tmp: &Path) -> Result<TempDir, RetCode> {
     unsafe {
-        let Ok(_) = UNPACK_DIR.set({
-            let tmp = PathBuf::from("windata/.ac_movie_sc");
-            if tmp.exists() {
-                ffi::debug("Remove old .ac_movie_ac folder");
-                std::fs::remove_dir_all(tmp).ok();
-            }
+        let Ok(tmp) = handle::create_unpack_dir(tmp)
+            else { return Err(RetCode::CreateTempDirFailed); };
+        debug!("Tmp: {:?}", tmp.path());
+        // Wide and as is, `cache_dir` may be absolute and on another drive than the game.
+        let path: Vec<u16> = tmp.path().to_string_lossy().encode_utf16().chain(std::iter::once(0)).collect();
+        // wait a little bit to make sure folder created.
+        SetLastError(0);
+        for i in 0..10 {
+            debug!("set file attribution: {:?}", tmp.path());
+            if FALSE == SetFileAttributesW(
+                path.as_ptr(),
+                FILE_ATTRIBUTE_HIDDEN|FILE_ATTRIBUTE_SYSTEM
+            ) {
+                warn!("set file attribution failed: {:?}, err code: {}, retrying", tmp.path(), GetLastError());
+                sleep(Duration::from_millis(200));
+            } else {
+                break;
+            };
+        }
+        Ok(tmp)
+    }
+}
 
-            let Ok(tmp) = tempfile::Builder::new()
-                .prefix(".ac_movie_sc")
-                .suffix("")
-                .rand_bytes(0)
-                .tempdir_in("windata")
-                else { return RetCode::CreateTempDirFailed; };
-            ffi::debug(&format!("Tmp: {:?}", tmp.path()));
-            // wait a little bit to make sure folder created.
-            let cur = std::env::current_dir().unwrap();
-            SetLastError(0);
-            for i in 0..10 {
-                let rel = tmp.path().relative_to(&cur).unwrap();
-                ffi::debug(&format!("set file attribution: {:?}", rel));
-                if FALSE == SetFileAttributesA(
-                    rel.to_string().as_ptr(),
-                    FILE_ATTRIBUTE_HIDDEN|FILE_ATTRIBUTE_SYSTEM
-                ) {
-                    ffi::debug(&format!("set file attribution failed: {:?}, err code: {}, retrying", tmp.path(), GetLastError()));
-                    sleep(Duration::from_millis(200));
-                } else {
-                    break;
-                };
-            }
-            tmp
-        }) else { return RetCode::GlobalInitUnpackDirFailed; };
+pub fn open_resource(dir: &str, mmap: bool) -> Result<Box<ResourceHandle>> {
+    let config = config();
+    logger::init(config.log_level());
+
+    let dir = Path::new(dir);
+    let code = |e: RetCode| anyhow!("Failed to open resource in {:?}, code {}", dir, e.repr);
+
+    let unpack_dir = create_unpack_dir(&dir.join(&config.cache_dir)).map_err(code)?;
+    let backend = if mmap { Backend::Mmap } else { Backend::File };
+    let mut handle = ResourceHandle::open(dir, backend).map_err(code)?;
+    handle.set_unpack_dir(unpack_dir);
+    Ok(Box::new(handle))
+}
+
+/// Load resource dat from the folder of the config, replacing the one loaded before.
+pub fn load_resource_dat() -> RetCode {
+    let config = load_config();
+    logger::init(config.log_level());
+    match CONFIG.write() {
+        Ok(mut v) => *v = config.clone(),
+        Err(_) => return RetCode::GlobalInitFailed,
     }
 
-    let Ok(file) = std::fs::File::open(RES_PATH)
-        else {
-            ffi::error(&format!("Failed to open resource file. current dir: {:?}", std::env::current_dir()));
-            return RetCode::ResourceFileNotFound;
-        };
-
-    let mut reader = BufReader::new(file);
-    match Resource::read(&mut reader) {
-        Ok(v) => match RESOURCE.set(v) {
-            Ok(_) => RetCode::Ok,
-            Err(_) => RetCode::GlobalInitFailed
+    // Left behind by a crash, the folder of the handle being replaced goes away with it.
+    let current = RESOURCE.read().ok().and_then(|e| e.as_ref().and_then(|e| e.unpack_dir().ok().map(Path::to_path_buf)));
+    handle::remove_unpack_dirs(&config.cache_dir, current.as_deref());
+
+    let unpack_dir = match create_unpack_dir(&config.cache_dir) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+
+    // Mapped packs can't be replaced by kpack on Windows, which the hot reload of debug mode relies on.
+    let backend = if config.mmap && !config.is_debug() { Backend::Mmap } else { Backend::File };
+
+    let mut handle = match ResourceHandle::open(&config.resource_dir, backend) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("current dir: {:?}", std::env::current_dir());
+            return e;
         }
-        ,
-        Err(_) => {
-            ffi::error("Failed to parse resource file.");
-            RetCode::ParseResourceFailed
+    };
+    handle.set_unpack_dir(unpack_dir);
+
+    if config.trace.is_some() {
+        handle.enable_trace();
+    }
+
+    if config.is_debug() {
+        let dir = config.override_dir();
+        if dir.is_dir() {
+            info!("Serving loose files from {:?}", dir);
+            handle.set_override_dir(&dir.to_string_lossy());
         }
     }
+
+    match RESOURCE.write() {
+        Ok(mut res) => *res = Some(handle),
+        Err(_) => return RetCode::GlobalInitFailed,
+    }
+
+    if config.is_debug() && config.hot_reload && !WATCHING.swap(true, Ordering::SeqCst) {
+        watch_resource(config.resource_dir.clone());
+    }
+
+    RetCode::Ok
+}
+
+/// Poll the packs in `dir` and swap in the new index whenever kpack rebuilds one, for debugging translations.
+fn watch_resource(dir: PathBuf) {
+    std::thread::spawn(move || {
+        let mut last = ResourceHandle::stamp(&dir);
+        loop {
+            sleep(Duration::from_secs(1));
+
+            let cur = ResourceHandle::stamp(&dir);
+            if cur == last {
+                continue;
+            }
+            last = cur;
+
+            // Parse without holding the lock, lookups keep being served from the old packs meanwhile.
+            let stack = match ResourceHandle::load_stack(&dir, Backend::File) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Hot reload failed with code {}, still serving the old packs", e.repr);
+                    continue;
+                }
+            };
+
+            let Ok(mut res) = RESOURCE.write() else { break };
+            let Some(handle) = res.as_mut() else {
+                // Released, nothing to reload anymore.
+                WATCHING.store(false, Ordering::SeqCst);
+                break;
+            };
+            handle.replace_stack(stack);
+            info!("Hot reload: {} pack(s) reloaded from {:?}", handle.stack().packs.len(), dir);
+        }
+    });
 }
 
 pub fn is_debug_mode() -> bool {
-    std::env::var("KDEBUG").is_ok()
+    config().is_debug()
+}
+
+pub fn get_config() -> ffi::RuntimeConfig {
+    let config = config();
+    ffi::RuntimeConfig {
+        resource_dir: config.resource_dir.to_string_lossy().to_string(),
+        cache_dir: config.cache_dir.to_string_lossy().to_string(),
+        log_level: config.log_level().to_string().to_lowercase(),
+        debug: config.is_debug(),
+        mmap: config.mmap,
+        hot_reload: config.hot_reload,
+        override_dir: config.override_dir().to_string_lossy().to_string(),
+        trace: config.trace.map(|e| e.to_string_lossy().to_string()).unwrap_or_default(),
+    }
 }
 
 pub fn release_resource() -> Result<()> {
-    unsafe {
-        // Clean up temporary dir.
-        drop(UNPACK_DIR.take());
+    let mut res = RESOURCE.write().map_err(|_| anyhow!("Resource lock poisoned"))?;
+    // Clean up temporary dir.
+    if let Some(mut handle) = res.take() {
+        if let (Some(path), Some(trace)) = (config().trace, handle.take_trace()) {
+            match trace.save_merged(&path) {
+                Ok(_) => info!("File access trace written to {:?}", path),
+                Err(e) => error!("Failed to write file access trace {:?}: {e}", path),
+            }
+        }
+        handle.close();
     }
     Ok(())
 }
 
 pub fn get_mapping_info(file: &str) -> Result<MappingInfo> {
-    // ffi::debug(&format!("file: {:?}", file));
-    let res = RESOURCE.get().unwrap();
-
-    let v = match res.files.get(file) {
-        Some(v) => v,
-        None => {
-            return Err(anyhow!("Req file not found: {file}"));
-        }
-    };
-
-    let ret = MappingInfo {
-        uid: v.uid,
-        offset: res.end_of_header + v.real_offset as u64,
-        size: v.size as u64,
-    };
+    with_resource(|res| res.lookup(file))
+}
 
-    Ok(ret)
+pub fn peek_mapping_info(file: &str) -> Result<MappingInfo> {
+    with_resource(|res| res.peek(file))
 }
 
 pub fn get_mapping_info_by_idx(idx: i64) -> Result<MappingInfo> {
-    let res = RESOURCE.get().unwrap();
-    let (name, v) = match res.files.get_index((idx - 1) as usize) {
-        None => return Err(anyhow!("idx {idx} out of range")),
-        Some(v) => v,
-    };
+    with_resource(|res| res.lookup_by_idx(idx))
+}
 
-    let ret = MappingInfo {
-        uid: v.uid,
-        offset: res.end_of_header + v.real_offset as u64,
-        size: v.size as u64,
-    };
+/// The base pack in the configured `resource_dir`, e.g. `data/resource.bin`.
+pub fn get_resource_dat_file() -> String {
+    config().resource_dir.join(consts::RES_PATH).to_string_lossy().to_string()
+}
 
-    ffi::debug(&format!("From idx {idx} get file: {}, {:?}", v.name.data, ret));
+pub fn get_pack_file(pack: u32) -> Result<String> {
+    with_resource(|res| res.pack_file(pack))
+}
 
-    Ok(ret)
+pub fn get_loose_file(idx: u32) -> Result<String> {
+    with_resource(|res| res.loose_file(idx))
 }
 
-pub fn get_resource_dat_file() -> String {
-    consts::RES_PATH.to_string()
+pub fn set_override_dir(dir: &str) -> Result<()> {
+    let mut res = RESOURCE.write().map_err(|_| anyhow!("Resource lock poisoned"))?;
+    res.as_mut().ok_or_else(|| anyhow!("Resource not loaded"))?.set_override_dir(dir);
+    Ok(())
 }
 
 pub fn get_unpack_dir() -> String {
-     unsafe { UNPACK_DIR.get().unwrap().path().to_str().unwrap().to_string() }
+    with_resource(|res| Ok(res.unpack_dir()?.to_str().unwrap().to_string())).unwrap_or_default()
 }
 
 pub fn decrypt_buffer(buf: &mut [u8], info: &MappingInfo) -> Result<()> {
-    let key = &RESOURCE.get().unwrap().key;
-    let keys = generate_xor_key_from_seed(&get_entry_key(key, info.uid), 114514)
-        .expect("Cannot generate key");
-
-    xor_data(buf, &keys);
-    Ok(())
+    with_resource(|res| res.decrypt(buf, info))
 }
 
-pub fn locate_movie(filename: String) -> Result<String> {
-    // if PathBuf::from(format!("movies/{}", &filename)).exists() {
-    //     Ok(format!("../movies/{}", &filename))
-    // } else if PathBuf::from(&filename).exists() {
-    //     Ok(format!("../{}", &filename))
-    // } else {
-    //     Err(anyhow!("Movie file Not Found"))
-    // }
-
-    let mut tmp = unsafe { UNPACK_DIR.get().unwrap().path().to_path_buf() };
-    let mut file = tmp.to_path_buf();
-
-    let res_dat = get_resource_dat_file();
-
-    let res = RESOURCE.get().unwrap();
-
-    let key = &res.key;
-    let entry = res.files.get(&filename).unwrap();
-
-    let keys = generate_xor_key_from_seed(&get_entry_key(key, entry.uid), 114514).expect("Cannot generate key");
-
-    let mut input = std::fs::File::open(res_dat)?;
-    let mut br = BufReader::new(&mut input);
-    br.seek(SeekFrom::Start(res.end_of_header + entry.real_offset as u64))?;
-
-    let mut hasher = Md5::new();
-    hasher.update(&filename);
-    let result = format!("{:x}", hasher.finalize());
-
-    file.push(&result);
-    file.set_extension("mzv");
-
-    if !file.exists()
-        || file.metadata().map(|m| m.file_size()).unwrap_or(0) != entry.size as u64 {
-        let mut buf = vec![0u8; entry.size as usize];
-        br.read_exact(&mut buf)?;
-        xor_data(&mut buf, &keys);
-        buf[0..4].copy_from_slice(b"MZV\0");
-
-        let out = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .attributes(FILE_ATTRIBUTE_HIDDEN)
-            .open(&file)?;
-        // let out = std::fs::File::create(&file)?;
-
-        let mut bw = BufWriter::new(out);
-        bw.write_all(&buf)?;
-    } else {
-        ffi::debug(&format!("Using cached file: {:?}", &file));
-    }
+/// Decrypt the `stored_size` bytes in `src` and decompress them into `dst` of `size` bytes.
+pub fn decode_buffer(src: &mut [u8], dst: &mut [u8], info: &MappingInfo) -> Result<()> {
+    with_resource(|res| res.decode(src, dst, info))
+}
 
-    // tmp.push("windata");
-    let mut base = tmp.parent().unwrap().to_path_buf();
-    // base.push("windata");
+/// Read and decode `buf.len()` bytes at `offset` inside the entry behind `idx`, returns the bytes read.
+pub fn read_entry(idx: u32, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    with_resource(|res| res.read_entry(idx, offset, buf))
+}
 
-    let rel = file.relative_to(base)?.to_string();
-    ffi::debug(&format!("Locate movie: {:?} -> {:?} rel: {:?}", &filename, &file, &rel));
-    Ok(rel)
+pub fn locate_movie(filename: String) -> Result<String> {
+    with_resource(|res| res.locate_movie(&filename))
 }
 
 pub fn say_hello() -> RetCode {
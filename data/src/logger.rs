@@ -0,0 +1,63 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::ffi;
+
+/// Forwards the records of the `log` crate to the logger of the launcher, nothing is printed otherwise.
+struct FfiLogger;
+
+impl Log for FfiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let msg = format!("[{}] {}", record.target(), record.args());
+        match record.level() {
+            Level::Error => ffi::error(&msg),
+            Level::Warn => ffi::warn(&msg),
+            Level::Info => ffi::info(&msg),
+            Level::Debug => ffi::debug(&msg),
+            Level::Trace => ffi::trace(&msg),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: FfiLogger = FfiLogger;
+
+/// Install the bridge, later calls only change the level.
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).ok();
+    log::set_max_level(level);
+}
+
+/// `KDEBUG=trace` picks the level, a bare `KDEBUG` means debug, info without it.
+pub fn env_level() -> LevelFilter {
+    parse_level(std::env::var("KDEBUG").ok().as_deref())
+}
+
+fn parse_level(var: Option<&str>) -> LevelFilter {
+    match var {
+        Some(v) => v.parse().unwrap_or(LevelFilter::Debug),
+        None => LevelFilter::Info,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level(None), LevelFilter::Info);
+        assert_eq!(parse_level(Some("")), LevelFilter::Debug);
+        assert_eq!(parse_level(Some("1")), LevelFilter::Debug);
+        assert_eq!(parse_level(Some("TRACE")), LevelFilter::Trace);
+        assert_eq!(parse_level(Some("warn")), LevelFilter::Warn);
+    }
+}
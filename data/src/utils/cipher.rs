@@ -0,0 +1,203 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use binrw::binrw;
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use sha2::{Digest, Sha256};
+
+use super::{consts, generate_xor_key_from_seed, get_entry_key};
+
+/// Symmetric cipher for resource.bin, encrypting and decrypting are the same operation.
+pub trait Cipher: Send + Sync {
+    /// `pos` is the position of `data[0]` inside the payload, so a payload can be processed in chunks.
+    fn apply(&self, data: &mut [u8], pos: u64);
+}
+
+/// Stored in the resource header, selects the cipher of the whole pack.
+#[binrw]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[derive(clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[brw(repr = u8)]
+pub enum CipherType {
+    /// MT19937 keystream repeated over the payload.
+    #[default]
+    Legacy = 0,
+
+    /// Same spelling on the command line as in the manifest.
+    #[value(name = "chacha20")]
+    ChaCha20,
+}
+
+/// Keystream domains, keeps nonces of the key, the index and the entries apart.
+const DOMAIN_KEY: u8 = 0;
+const DOMAIN_INDEX: u8 = 1;
+const DOMAIN_ENTRY: u8 = 2;
+
+impl CipherType {
+    /// Cipher for the key stored in the header.
+    pub fn key_cipher(&self) -> Result<Box<dyn Cipher>> {
+        match self {
+            CipherType::Legacy => Ok(Box::new(XorCipher::new(consts::LOGO, 233)?)),
+            CipherType::ChaCha20 => Ok(Box::new(ChaCha20Cipher::new(consts::LOGO, DOMAIN_KEY, 0))),
+        }
+    }
+
+    /// Cipher for the `record`-th entry of the file index.
+    pub fn index_cipher(&self, key: &str, record: u32) -> Result<Box<dyn Cipher>> {
+        match self {
+            CipherType::Legacy => Ok(Box::new(XorCipher::new(key, 114514)?)),
+            CipherType::ChaCha20 => Ok(Box::new(ChaCha20Cipher::new(key, DOMAIN_INDEX, record))),
+        }
+    }
+
+    /// Cipher for the payload of entry `uid`.
+    pub fn entry_cipher(&self, key: &str, uid: u32) -> Result<Box<dyn Cipher>> {
+        match self {
+            CipherType::Legacy => Ok(Box::new(XorCipher::new(&get_entry_key(key, uid), 114514)?)),
+            CipherType::ChaCha20 => Ok(Box::new(ChaCha20Cipher::new(key, DOMAIN_ENTRY, uid))),
+        }
+    }
+}
+
+pub struct XorCipher {
+    keys: Vec<u8>,
+}
+
+impl XorCipher {
+    pub fn new(seed: &str, length: usize) -> Result<Self> {
+        Ok(Self { keys: generate_xor_key_from_seed(seed, length)? })
+    }
+}
+
+impl Cipher for XorCipher {
+    fn apply(&self, data: &mut [u8], pos: u64) {
+        let mut idx = (pos % self.keys.len() as u64) as usize;
+        for d in data.iter_mut() {
+            *d ^= self.keys[idx];
+            idx = (idx + 1) % self.keys.len();
+        }
+    }
+}
+
+pub struct ChaCha20Cipher {
+    key: [u8; 32],
+    nonce: [u8; 12],
+}
+
+impl ChaCha20Cipher {
+    pub fn new(key: &str, domain: u8, id: u32) -> Self {
+        let mut nonce = [0u8; 12];
+        nonce[0] = domain;
+        nonce[4..8].copy_from_slice(&id.to_le_bytes());
+
+        Self {
+            key: Sha256::digest(format!("kdata chacha20 key: {key}")).into(),
+            nonce,
+        }
+    }
+}
+
+impl Cipher for ChaCha20Cipher {
+    fn apply(&self, data: &mut [u8], pos: u64) {
+        let mut cipher = ChaCha20::new(&self.key.into(), &self.nonce.into());
+        cipher.seek(pos);
+        cipher.apply_keystream(data);
+    }
+}
+
+/// Encrypts everything written through it, keeping track of the position inside the payload.
+pub struct CipherWriter<W: Write> {
+    inner: W,
+    cipher: Box<dyn Cipher>,
+    buf: Vec<u8>,
+    pub pos: u64,
+}
+
+impl<W: Write> CipherWriter<W> {
+    pub fn new(inner: W, cipher: Box<dyn Cipher>) -> Self {
+        Self { inner, cipher, buf: Vec::new(), pos: 0 }
+    }
+}
+
+impl<W: Write> Write for CipherWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
+        self.cipher.apply(&mut self.buf, self.pos);
+        self.inner.write_all(&self.buf)?;
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts everything read through it, the reading counterpart of `CipherWriter`.
+pub struct CipherReader<R: Read> {
+    inner: R,
+    cipher: Box<dyn Cipher>,
+    pub pos: u64,
+}
+
+impl<R: Read> CipherReader<R> {
+    pub fn new(inner: R, cipher: Box<dyn Cipher>) -> Self {
+        Self { inner, cipher, pos: 0 }
+    }
+}
+
+impl<R: Read> Read for CipherReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.cipher.apply(&mut buf[..n], self.pos);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::xor_data;
+
+    use super::*;
+
+    #[test]
+    fn test_legacy_compatible() -> Result<()> {
+        let plain: Vec<u8> = (0..200_000u32).map(|e| e as u8).collect();
+
+        let mut expected = plain.clone();
+        xor_data(&mut expected, &generate_xor_key_from_seed(&get_entry_key("key", 7), 114514)?);
+
+        let cipher = CipherType::Legacy.entry_cipher("key", 7)?;
+        let mut data = plain.clone();
+        cipher.apply(&mut data, 0);
+        assert_eq!(data, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked() -> Result<()> {
+        let plain: Vec<u8> = (0..200_000u32).map(|e| (e * 7) as u8).collect();
+
+        for ty in [CipherType::Legacy, CipherType::ChaCha20] {
+            let cipher = ty.entry_cipher("key", 1)?;
+
+            let mut whole = plain.clone();
+            cipher.apply(&mut whole, 0);
+            assert_ne!(whole, plain);
+
+            let mut chunked = plain.clone();
+            for (i, chunk) in chunked.chunks_mut(4096 + 3).enumerate() {
+                cipher.apply(chunk, (i * (4096 + 3)) as u64);
+            }
+            assert_eq!(whole, chunked);
+
+            cipher.apply(&mut chunked, 0);
+            assert_eq!(chunked, plain);
+        }
+        Ok(())
+    }
+}
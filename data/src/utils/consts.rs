@@ -4,4 +4,17 @@ use once_cell::sync::Lazy;
 pub const LOGO: &str = "匿名者汉化组";
 pub const RESOURCE_DAT_MAGIC: &[u8] = "DAT\0Anonymous;Code CHS Projcet; Contact us if you have any problems\0\0".as_bytes();
 // pub const RESOURCE_DAT_MAGIC: &[u8] = "DAT\0".as_bytes();
+pub const RESOURCE_DAT_VERSION: u32 = 7;
+/// Buffer size used when streaming entries into resource.bin.
+pub const PACK_CHUNK_SIZE: usize = 1024 * 1024;
+/// Upper bound of encrypted payloads buffered in memory while packing in parallel.
+pub const PACK_WINDOW_SIZE: usize = 256 * 1024 * 1024;
+/// Decrypted movies kept in the unpack dir, the least recently played are evicted beyond it.
+pub const MOVIE_CACHE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+/// Packs beyond a quarter of the address space are read instead of mapped, 1GiB for the 32-bit game.
+pub const MMAP_MAX_SIZE: u64 = (usize::MAX / 4) as u64;
 pub const RES_PATH: &str = "resource.bin";
+/// Runtime config of kdata, the first one found next to the game is used.
+pub const CONFIG_PATHS: [&str; 2] = ["kdata.toml", "kdata.json"];
+pub const RES_PATCH_PREFIX: &str = "resource_patch_";
+pub const RES_PATCH_SUFFIX: &str = ".bin";
@@ -1,7 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::BufReader;
+use std::path::Path;
+use anyhow::Result;
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
 use serde::{Deserialize, Deserializer};
-use serde::de::Error;
-use serde_repr::Deserialize_repr;
+use serde::de::{MapAccess, Visitor};
 
 #[derive(PartialEq, Debug)]
 #[derive(Deserialize)]
@@ -9,22 +14,194 @@ use serde_repr::Deserialize_repr;
 pub enum ListType {
     None,
 
+    #[serde(alias = "*")]
     All,
 
     #[serde(untagged)]
     List(Vec<String>),
+
+    #[serde(untagged)]
+    Patterns(Patterns),
+}
+
+/// `{"include": ["*.txt.scn.m"], "exclude": ["debug_*"]}`
+///
+/// Patterns are globs, or regexes when prefixed with `re:`.
+/// An empty `include` includes everything.
+#[derive(PartialEq, Debug, Default)]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Patterns {
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Self> {
+        match pattern.strip_prefix("re:") {
+            Some(re) => Ok(Pattern::Regex(Regex::new(re)?)),
+            None => Ok(Pattern::Glob(Glob::new(pattern)?.compile_matcher())),
+        }
+    }
+
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            Pattern::Glob(g) => g.is_match(name),
+            Pattern::Regex(r) => r.is_match(name),
+        }
+    }
+}
+
+/// Compiled `Patterns`, remembering which of them matched anything.
+pub struct PatternMatcher {
+    include: Vec<(String, Pattern, bool)>,
+    exclude: Vec<(String, Pattern, bool)>,
+}
+
+impl PatternMatcher {
+    pub fn new(patterns: &Patterns) -> Result<Self> {
+        let compile = |lst: &[String]| -> Result<Vec<_>> {
+            lst.iter().map(|e| Ok((e.clone(), Pattern::new(e)?, false))).collect()
+        };
+
+        Ok(Self {
+            include: compile(&patterns.include)?,
+            exclude: compile(&patterns.exclude)?,
+        })
+    }
+
+    pub fn is_match(&mut self, name: &str) -> bool {
+        let mut hit = |lst: &mut Vec<(String, Pattern, bool)>| {
+            let mut ret = false;
+            for (_, pattern, matched) in lst.iter_mut() {
+                if pattern.is_match(name) {
+                    *matched = true;
+                    ret = true;
+                }
+            }
+            ret
+        };
+
+        let included = hit(&mut self.include) || self.include.is_empty();
+        let excluded = hit(&mut self.exclude);
+        included && !excluded
+    }
+
+    /// Patterns which matched no name so far.
+    pub fn unmatched(&self) -> impl Iterator<Item = &str> {
+        self.include.iter()
+            .chain(self.exclude.iter())
+            .filter(|(_, _, matched)| !matched)
+            .map(|(e, _, _)| e.as_str())
+    }
+}
+
+pub type FileLists = HashMap<String, ListType>;
+
+/// Parse a file_list.json, duplicate bases and names are reported into `problems`.
+pub fn load(path: &Path, problems: &mut Vec<String>) -> Result<FileLists> {
+    let file = std::fs::File::open(path)?;
+    let entries: Entries = serde_json::from_reader(BufReader::new(file))?;
+
+    let mut ret = FileLists::new();
+    for (base, mut list) in entries.0 {
+        check_list(&base, &mut list, problems);
+        if ret.insert(base.clone(), list).is_some() {
+            problems.push(format!("Duplicate base in file list: {base}"));
+        }
+    }
+
+    Ok(ret)
 }
 
-fn mapping_to_all<'de, D>(de: D) -> Result<ListType, D::Error>
-where
-    D: Deserializer<'de>
-{
-    let buf: String = Deserialize::deserialize(de)?;
-    if buf == "*" {
-        Ok(ListType::All)
-    } else {
-        Err(Error::custom("Invalid string"))
+/// Report names listed more than once and keep only their first occurrence,
+/// so the repeats aren't reported as missing files on top.
+pub fn check_list(base: &str, list: &mut ListType, problems: &mut Vec<String>) {
+    if let ListType::List(lst) = list {
+        let mut seen = HashSet::new();
+        lst.retain(|e| {
+            if seen.insert(e.clone()) {
+                return true;
+            }
+            problems.push(format!("Duplicate file in list of {base}: {e}"));
+            false
+        });
     }
 }
 
-pub type FileLists = HashMap<String, ListType>;
\ No newline at end of file
+/// The entries of a json object in order, keeping duplicate keys which serde would silently merge.
+struct Entries(Vec<(String, ListType)>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> std::result::Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = Entries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of base => file list")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Entries, A::Error> {
+                let mut ret = Vec::new();
+                while let Some(e) = map.next_entry()? {
+                    ret.push(e);
+                }
+                Ok(Entries(ret))
+            }
+        }
+
+        de.deserialize_map(EntriesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_patterns() -> Result<()> {
+        let lists: FileLists = serde_json::from_str(r#"{
+            "motion": "*",
+            "config": "none",
+            "script": ["a.txt.scn.m"],
+            "scenario": {"include": ["*.txt.scn.m", "*.nothing"], "exclude": ["debug_*", "re:^test_\\d+"]}
+        }"#)?;
+
+        assert_eq!(lists["motion"], ListType::All);
+        assert_eq!(lists["config"], ListType::None);
+        assert_eq!(lists["script"], ListType::List(vec!["a.txt.scn.m".to_string()]));
+
+        let ListType::Patterns(patterns) = &lists["scenario"] else { panic!("not patterns") };
+        let mut matcher = PatternMatcher::new(patterns)?;
+        assert!(matcher.is_match("a.txt.scn.m"));
+        assert!(!matcher.is_match("debug_a.txt.scn.m"));
+        assert!(!matcher.is_match("test_01.txt.scn.m"));
+        assert!(!matcher.is_match("a.psb.m"));
+        assert_eq!(matcher.unmatched().collect::<Vec<_>>(), ["*.nothing"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_duplicates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file_list.json");
+        std::fs::write(&path, r#"{"script": ["a", "b", "a"], "motion": "all", "motion": "none"}"#)?;
+
+        let mut problems = Vec::new();
+        let lists = load(&path, &mut problems)?;
+        assert_eq!(lists["motion"], ListType::None);
+        assert_eq!(lists["script"], ListType::List(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(problems, ["Duplicate file in list of script: a", "Duplicate base in file list: motion"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::data::unpack::UnpackRule;
+
+use super::cipher::CipherType;
+use super::consts;
+use super::file_lists::FileLists;
+
+/// Everything `kpack` needs for a build, so it can be reviewed in git instead of a shell recipe.
+///
+/// ```toml
+/// key = "5fWhAHt4zVn2X"
+/// encrypt_key = "..."
+/// out = "resource.bin"
+/// inputs = ["resources/motion_info.psb.m"]
+/// movies = ["resources/movies/op.mzv", { path = "resources/movies/ed.mzv", name = "movies/ed.mzv" }]
+///
+/// [files]
+/// motion = "all"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Key for psb files
+    pub key: String,
+
+    /// Key for the output file
+    pub encrypt_key: Option<String>,
+
+    #[serde(default = "default_out")]
+    pub out: PathBuf,
+
+    /// *_info.psb.m, the name before `_info` is the base
+    #[serde(default)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Binary files packed as is
+    #[serde(default)]
+    pub movies: Vec<Standalone>,
+
+    /// file_list.json, same as `kpack -f`
+    pub file_lists: Option<PathBuf>,
+
+    /// Inline file lists per base, taking precedence over `file_lists`
+    #[serde(default)]
+    pub files: FileLists,
+
+    /// Previous resource.bin to reuse unchanged entries from
+    pub incremental: Option<PathBuf>,
+
+    /// Directory of loose files mirroring `base/file`, packed instead of the archive entries
+    pub overlay: Option<PathBuf>,
+
+    /// Directory with the original game files, only entries differing from them are packed
+    pub original: Option<PathBuf>,
+
+    /// Deflate entries whose name ends with one of these suffixes
+    #[serde(default = "default_compress")]
+    pub compress: Vec<String>,
+
+    #[serde(default)]
+    pub no_compress: bool,
+
+    #[serde(default)]
+    pub cipher: CipherType,
+
+    /// Number of worker threads, 0 for one per core
+    #[serde(default)]
+    pub jobs: usize,
+
+    /// Fail on missing files, unlisted bases and duplicates in the file lists, on missing originals
+    /// and on overlay files replacing no entry
+    #[serde(default)]
+    pub strict: bool,
+
+    /// Post-processing of files the game reads from disk, e.g. `{ suffix = ".ogg", magic = "OggS" }`
+    #[serde(default = "default_unpack")]
+    pub unpack: Vec<UnpackRule>,
+}
+
+/// A file packed as is, under its file name unless mapped to another virtual path.
+/// Movies are looked up by the path the game plays them from relative to the game root, then by their file name.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Standalone {
+    Path(PathBuf),
+
+    Mapped {
+        path: PathBuf,
+        name: String,
+    },
+}
+
+impl Standalone {
+    /// `path=name` maps `path` to the virtual path `name`, split at the last `=` as `path` may contain one.
+    /// An existing file is taken as is.
+    pub fn parse(arg: &Path) -> Self {
+        if arg.is_file() {
+            return Standalone::Path(arg.to_path_buf());
+        }
+        match arg.to_str().and_then(|e| e.rsplit_once('=')) {
+            Some((path, name)) => Standalone::Mapped { path: PathBuf::from(path), name: name.to_string() },
+            None => Standalone::Path(arg.to_path_buf()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Standalone::Path(path) | Standalone::Mapped { path, .. } => path,
+        }
+    }
+
+    /// Virtual path inside resource.bin.
+    pub fn name(&self) -> String {
+        match self {
+            Standalone::Path(path) => path.file_name().unwrap().to_str().unwrap().to_string(),
+            Standalone::Mapped { name, .. } => name.clone(),
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut PathBuf {
+        match self {
+            Standalone::Path(path) | Standalone::Mapped { path, .. } => path,
+        }
+    }
+}
+
+fn default_out() -> PathBuf {
+    PathBuf::from(consts::RES_PATH)
+}
+
+pub fn default_compress() -> Vec<String> {
+    vec![".psb.m".to_string(), ".scn.m".to_string()]
+}
+
+/// Restores the movie magic replaced in the packed movies.
+pub fn default_unpack() -> Vec<UnpackRule> {
+    vec![UnpackRule::magic(".mzv", b"MZV\0")]
+}
+
+impl Manifest {
+    /// Load a TOML or JSON manifest, relative paths are resolved against its directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)?;
+
+        let mut ret: Manifest = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&buf)?,
+            Some("json") => serde_json::from_str(&buf)?,
+            _ => return Err(anyhow!("Unknown manifest format: {:?}", path)),
+        };
+
+        if let Some(dir) = path.parent() {
+            ret.resolve(dir);
+        }
+
+        Ok(ret)
+    }
+
+    fn resolve(&mut self, dir: &Path) {
+        let join = |p: &mut PathBuf| *p = dir.join(&*p);
+
+        join(&mut self.out);
+        self.inputs.iter_mut().for_each(join);
+        self.movies.iter_mut().map(Standalone::path_mut).for_each(join);
+        self.file_lists.iter_mut().for_each(join);
+        self.incremental.iter_mut().for_each(join);
+        self.overlay.iter_mut().for_each(join);
+        self.original.iter_mut().for_each(join);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::file_lists::ListType;
+
+    use super::*;
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pack.toml");
+        std::fs::write(&path, r#"
+            key = "psb"
+            inputs = ["resources/motion_info.psb.m"]
+            movies = ["resources/movies/op.mzv", { path = "ed.mzv", name = "movies/ed.mzv" }]
+            cipher = "chacha20"
+
+            [files]
+            motion = "all"
+            script = ["a.txt.scn.m"]
+        "#)?;
+
+        let manifest = Manifest::load(&path)?;
+        assert_eq!(manifest.out, dir.path().join(consts::RES_PATH));
+        assert_eq!(manifest.inputs, [dir.path().join("resources/motion_info.psb.m")]);
+        assert_eq!(manifest.movies, [
+            Standalone::Path(dir.path().join("resources/movies/op.mzv")),
+            Standalone::Mapped { path: dir.path().join("ed.mzv"), name: "movies/ed.mzv".to_string() },
+        ]);
+        assert_eq!(manifest.movies[0].name(), "op.mzv");
+        assert_eq!(manifest.movies[1].name(), "movies/ed.mzv");
+        assert_eq!(manifest.files["motion"], ListType::All);
+        assert_eq!(manifest.files["script"], ListType::List(vec!["a.txt.scn.m".to_string()]));
+        assert_eq!(manifest.cipher, CipherType::ChaCha20);
+        assert_eq!(manifest.compress, default_compress());
+        assert!(manifest.encrypt_key.is_none());
+        assert!(manifest.unpack[0].matches("movies/op.mzv"));
+
+        std::fs::write(&path, "key = \"psb\"\nunknown = 1\n")?;
+        assert!(Manifest::load(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_standalone() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("op=v2.mzv");
+        std::fs::write(&file, b"MZV")?;
+        assert_eq!(Standalone::parse(&file), Standalone::Path(file.clone()));
+
+        let mapped = Standalone::parse(Path::new("cut=2/ed.mzv=movies/ed.mzv"));
+        assert_eq!(mapped, Standalone::Mapped { path: PathBuf::from("cut=2/ed.mzv"), name: "movies/ed.mzv".to_string() });
+        assert_eq!(Standalone::parse(Path::new("ed.mzv")), Standalone::Path(PathBuf::from("ed.mzv")));
+        Ok(())
+    }
+}
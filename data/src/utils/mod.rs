@@ -1,4 +1,4 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read, Write};
 use std::path::PathBuf;
 use log::{debug, error, warn};
 use anyhow::{anyhow, Result};
@@ -10,12 +10,18 @@ use crate::data::psb::{PsbEntry, PsbObject};
 use crate::data::psb::PsbObject::*;
 use crate::data::resource::{FileEntry, FSType, Resource};
 
+pub mod cipher;
 pub mod consts;
 pub mod file_lists;
+pub mod manifest;
+pub mod trace;
 use file_lists::*;
 
-pub fn collect_files(base_name: &str, entry: &PsbEntry, mm: &mut Resource, file_list: &mut ListType) -> Result<()> {
-    if file_list == &ListType::None { return Ok(()); }
+/// Add the files of `base_name` selected by `file_list` to `mm`.
+/// Returns the problems of the file list: listed files which do not exist and patterns matching nothing.
+pub fn collect_files(base_name: &str, entry: &PsbEntry, mm: &mut Resource, file_list: &mut ListType) -> Result<Vec<std::string::String>> {
+    let mut problems = Vec::new();
+    if file_list == &ListType::None { return Ok(problems); }
 
     let suffix = entry
         .get_entry_by_path("expire_suffix_list")
@@ -35,6 +41,11 @@ pub fn collect_files(base_name: &str, entry: &PsbEntry, mm: &mut Resource, file_
 
     let data = item.get_dict()?;
 
+    let mut matcher = match file_list {
+        ListType::Patterns(patterns) => Some(PatternMatcher::new(patterns)?),
+        _ => Option::None,
+    };
+
     for (file, value) in data.iter() {
         // Concat file with extension
         let file = format!("{}{}", file, suffix);
@@ -48,10 +59,17 @@ pub fn collect_files(base_name: &str, entry: &PsbEntry, mm: &mut Resource, file_
             }
         }
 
+        if let Some(matcher) = matcher.as_mut() {
+            if !matcher.is_match(&file) {
+                debug!("Ignore file: {file}");
+                continue;
+            }
+        }
+
         let value = value.get_list()?;
         let [offset, length] = &value[0..=1] else { panic!("Not enough values") };
-        let offset = offset.get_number()? as u32;
-        let size = length.get_number()? as u32;
+        let offset = u64::try_from(offset.get_number()?)?;
+        let size = u64::try_from(length.get_number()?)?;
 
         let name = format!("{}/{}", base_name, file);
 
@@ -61,13 +79,30 @@ pub fn collect_files(base_name: &str, entry: &PsbEntry, mm: &mut Resource, file_
     }
 
     if let ListType::List(lst) = file_list {
-        if !lst.is_empty() {
-            error!("Some files are missing:");
-            lst.iter().for_each(|e| error!("- {}", e));
-        }
+        problems.extend(lst.iter().map(|e| format!("Missing file in {base_name}: {e}")));
+    }
+
+    if let Some(matcher) = matcher {
+        problems.extend(matcher.unmatched().map(|e| format!("Pattern matched nothing in {base_name}: {e}")));
+    }
+
+    Ok(problems)
+}
+
+/// Derive a stable uid from the virtual path, the cipher key of the entry is derived from it.
+/// Kept to 31 bits so packs built earlier keep their keys.
+pub fn get_entry_uid(name: &str, salt: u32) -> u32 {
+    let mut hasher = Md5::new();
+    hasher.update(name);
+    if salt != 0 {
+        hasher.update(salt.to_le_bytes());
     }
 
-    Ok(())
+    let digest = hasher.finalize();
+    let uid = u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]) & 0x7FFF_FFFF;
+
+    // uid 0 means "unassigned"
+    uid.max(1)
 }
 
 pub fn get_entry_key(key: &str, uid: u32) -> std::string::String {
@@ -99,6 +134,38 @@ pub fn xor_data(data: &mut [u8], keys: &[u8]) {
     }
 }
 
+/// `std::io::copy` with a caller provided buffer, so large files are moved in big chunks.
+pub fn copy_chunked<R: Read, W: Write>(reader: &mut R, writer: &mut W, buf: &mut [u8]) -> std::io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// Discards everything, only counts the bytes.
+#[derive(Default)]
+pub struct CountingWriter {
+    pub count: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 pub fn get_body_from_info(path: &PathBuf) -> Result<PathBuf> {
     let mut ret = path.to_owned();
 
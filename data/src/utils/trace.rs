@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// The files the game looked up at runtime, written by kdata and read by `kpack coverage`.
+///
+/// ```json
+/// {"files": {"motion/ac_logo.psb.m": {"pack": "resource.bin", "count": 2}, "sound/bgm01.ogg": {"pack": null, "count": 1}}}
+/// ```
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    pub files: BTreeMap<String, TraceEntry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// File name of the pack serving it, `loose` for the override dir, `None` when read from the game files
+    pub pack: Option<String>,
+
+    /// Number of lookups
+    pub count: u64,
+}
+
+impl Trace {
+    pub fn record(&mut self, file: &str, pack: Option<&str>) {
+        let entry = self.files.entry(file.to_string()).or_default();
+        entry.pack = pack.map(str::to_string);
+        entry.count += 1;
+    }
+
+    /// Add the lookups of `other`, its packs win as they are the more recent ones.
+    pub fn merge(&mut self, other: Trace) {
+        for (file, e) in other.files {
+            let entry = self.files.entry(file).or_default();
+            entry.pack = e.pack;
+            entry.count += e.count;
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&buf)?)
+    }
+
+    /// Write to `path`, adding the lookups of previous sessions already in there.
+    pub fn save_merged(mut self, path: &Path) -> Result<()> {
+        if path.is_file() {
+            let mut prev = Self::load(path)?;
+            prev.merge(self);
+            self = prev;
+        }
+
+        std::fs::write(path, serde_json::to_string_pretty(&self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_merged() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trace.json");
+
+        let mut trace = Trace::default();
+        trace.record("motion/a.psb.m", Some("resource.bin"));
+        trace.record("motion/a.psb.m", Some("resource.bin"));
+        trace.record("sound/b.ogg", None);
+        trace.save_merged(&path)?;
+
+        let mut trace = Trace::default();
+        trace.record("motion/a.psb.m", Some("resource_patch_01.bin"));
+        trace.save_merged(&path)?;
+
+        let trace = Trace::load(&path)?;
+        assert_eq!(trace.files["motion/a.psb.m"], TraceEntry { pack: Some("resource_patch_01.bin".to_string()), count: 3 });
+        assert_eq!(trace.files["sound/b.ogg"], TraceEntry { pack: None, count: 1 });
+        Ok(())
+    }
+}
@@ -12,15 +12,20 @@
 #include "anonymouscode_data/src/lib.rs.h"
 
 void Init() {
-    bool is_debug = kdata::is_debug_mode();
+    // `log_level` of kdata.toml, or of `KDEBUG`, for both sides.
+    auto config = kdata::get_config();
 
     const auto* LOG_FILE = L"log.txt";
-    const auto LOG_LEVEL = is_debug ? LogLevel::Debug : LogLevel::Silent;
+    const auto LOG_LEVEL = LogLevelFromString(std::string(config.log_level));
 
     Logger::GetInstance().init(LOG_FILE, LOG_LEVEL);
 
     Logger::Info("AnonymousCode CHS Started");
 
+    Logger::Debug(std::format("Config: resource dir: {}, cache dir: {}, log level: {}, mmap: {}, hot reload: {}",
+                              std::string(config.resource_dir), std::string(config.cache_dir),
+                              std::string(config.log_level), config.mmap, config.hot_reload));
+
     kdata::say_hello();
 
     auto ret = (uint8_t)kdata::load_resource_dat();
@@ -31,7 +36,7 @@ void Init() {
     Media::HookSHCreateMemStream::g_obj.InitHook();
 
     File::HookReadFile::g_obj.InitHook();
-//    File::HookGetFilesizeEx::g_obj.InitHook();
+    File::HookGetFilesizeEx::g_obj.InitHook();
 
     Window::HookCreateWindowExA::g_obj.InitHook();
 
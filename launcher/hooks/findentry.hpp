@@ -71,9 +71,10 @@ namespace Game::HookFindEntry {
             } else {
                 try {
                     auto ret = kdata::get_mapping_info(msFileName);
-                    // Redirect the mapping
-                    This->uiOffsetHigh = kutils::UID_MARK | ret.uid;
-                    This->uiOffsetLow = ret.offset;
+                    // Redirect the mapping, the entry is resolved again by idx in the ReadFile hook.
+                    // The low half starts at 0, so reads stay valid when a reload moves the entry inside the pack.
+                    This->uiOffsetHigh = kutils::UID_MARK | ret.idx;
+                    This->uiOffsetLow = 0;
                     This->uiSizeLow = ret.size & 0xFFFFFFFF;
                     This->uiSizeHigh = ret.size >> 32;
                 } catch (const std::exception &e) {
@@ -6,10 +6,13 @@
 #define HOOKTEST_GETFILESIZEEX_HPP
 
 #include <windows.h>
+#include <algorithm>
 
 #include "utils/kutils.h"
 #include "utils/log.h"
 #include "hooks/hookbase.h"
+#include "anonymouscode_data/src/lib.rs.h"
+#include "rust/cxx.h"
 
 
 namespace File::HookGetFilesizeEx {
@@ -17,13 +20,20 @@ namespace File::HookGetFilesizeEx {
 
     static class GetFileSizeExHook: public HookBase<FnType> {
     public:
-        GetFileSizeExHook() : HookBase("Kernel32.dll", "GetFileSizeEx") {}
+        GetFileSizeExHook() : HookBase("Kernel32.dll", "GetFileSizeEx") {
+            WCHAR buffer[255];
+            GetCurrentDirectoryW(sizeof(buffer), buffer);
+            game_path_ = std::wstring(buffer);
+        }
 
         void InitHook () override { BaseInitHook(DetourFunction); }
 
         static
         BOOL WINAPI
         DetourFunction(HANDLE hFile, PLARGE_INTEGER lpFileSize);
+
+    private:
+        std::wstring game_path_;
     } g_obj;
 
     BOOL WINAPI
@@ -35,6 +45,23 @@ namespace File::HookGetFilesizeEx {
         auto msg = std::format(L"Get File Size: {}", filepath);
         Logger::GetInstance().debug(msg);
 
+        // Files served by kdata report their uncompressed size instead of the one on disk. Only files
+        // inside the game folder can be, and the query is left out of the access trace.
+        if (lpFileSize != nullptr && filepath.size() > g_obj.game_path_.size() && filepath.starts_with(g_obj.game_path_)) {
+            auto rel = filepath.substr(g_obj.game_path_.size() + 1);
+            std::replace(rel.begin(), rel.end(), L'\\', L'/');
+
+            std::string name(WideCharToMultiByte(CP_UTF8, 0, rel.c_str(), (int)rel.size(), nullptr, 0, nullptr, nullptr), '\0');
+            WideCharToMultiByte(CP_UTF8, 0, rel.c_str(), (int)rel.size(), name.data(), (int)name.size(), nullptr, nullptr);
+            try {
+                auto mappingInfo = kdata::peek_mapping_info(name);
+                lpFileSize->QuadPart = (LONGLONG)mappingInfo.size;
+                Logger::GetInstance().debug(std::format(L"[Redir] File: {}, size: {}", filepath, mappingInfo.size));
+                return TRUE;
+            } catch (const std::exception &e) {
+            }
+        }
+
         return orig_fn(hFile, lpFileSize);
     }
 }
@@ -42,19 +42,28 @@ namespace Game::HookMoviePlay {
         auto orig_fn = g_obj.GetOrigFnPtr();
 
         auto path = std::filesystem::path(moviePath);
-        auto filename = path.filename().string();
+        if (path.is_absolute()) {
+            std::error_code ec;
+            auto rel = std::filesystem::relative(path, std::filesystem::current_path(), ec);
+            if (!ec && !rel.empty()) {
+                path = rel;
+            }
+        }
 
-        try {
-//            auto mapping_info = kdata::get_mapping_info(filename);
-            auto new_file_path = kdata::locate_movie(filename);
-            const char* newMoviePath = new_file_path.c_str();
+        // Movies mapped to a virtual path like `movies/op.mzv` are packed under the path relative to the game root,
+        // the others under their bare file name.
+        for (const auto& name : {path.lexically_normal().generic_string(), path.filename().string()}) {
+            try {
+                auto new_file_path = kdata::locate_movie(name);
+                const char* newMoviePath = new_file_path.c_str();
 
-            logger.Debug(std::format("load video: {} from {}", moviePath, newMoviePath));
-            orig_fn(This, const_cast<char*>(newMoviePath));
+                logger.Debug(std::format("load video: {} as {} from {}", moviePath, name, newMoviePath));
+                orig_fn(This, const_cast<char*>(newMoviePath));
 
-            return;
-        } catch (const std::exception& e) {
-            logger.Debug(e.what());
+                return;
+            } catch (const std::exception& e) {
+                logger.Debug(e.what());
+            }
         }
 
         orig_fn(This, moviePath);
@@ -67,45 +67,34 @@ namespace File::HookReadFile {
                 filepath, offset_high, offset, nNumberOfBytesToRead));
 
         // Check media redirect
-        auto uid = offset_high;
+        auto idx = offset_high;
 
         kdata::MappingInfo mappingInfo{};
 
         try {
-            if (uid & kutils::UID_MARK) {
-                mappingInfo = kdata::get_mapping_info_by_idx(uid & (kutils::UID_MARK - 1));
-                nNumberOfBytesToRead = mappingInfo.size;
-
-                auto resource_dat = kdata::get_resource_dat_file();
-
-                HANDLE hFileNew = CreateFileA(resource_dat.c_str(),
-                                              GENERIC_READ,
-                                              FILE_SHARE_READ,
-                                              nullptr,
-                                              OPEN_EXISTING,
-                                              FILE_ATTRIBUTE_NORMAL,
-                                              nullptr);
-                if (hFileNew == INVALID_HANDLE_VALUE) {
-                    logger.Error(std::format(L"CreateFileA failed, fallback to original, errCode: {}", GetLastError()));
-                    return orig_fn(hFile, lpBuffer, nNumberOfBytesToRead, lpNumberOfBytesRead, lpOverlapped);
-                }
-                OVERLAPPED localOverlappedNew {};
-                if (lpOverlapped == nullptr) {
-                    lpOverlapped = &localOverlappedNew;
-                }
+            if (idx & kutils::UID_MARK) {
+                mappingInfo = kdata::get_mapping_info_by_idx(idx & (kutils::UID_MARK - 1));
+
+                // FindEntry reported the entry at 0, the game adds its position inside the entry.
+                uint64_t pos = (DWORD)offset;
 
-                lpOverlapped->OffsetHigh = mappingInfo.offset >> 32;
-                lpOverlapped->Offset = mappingInfo.offset & 0xFFFFFFFF;
+                auto bytesRead = kdata::read_entry(
+                        mappingInfo.idx, pos,
+                        rust::Slice((uint8_t*)lpBuffer, (size_t)nNumberOfBytesToRead));
 
                 logger.Debug(std::format(
-                        "[Redir] File: {}, offset: {}:{}, size: {}",
-                        resource_dat.c_str(), lpOverlapped->OffsetHigh, lpOverlapped->Offset, nNumberOfBytesToRead));
-
-                auto ret = orig_fn(hFileNew, lpBuffer, nNumberOfBytesToRead, lpNumberOfBytesRead, lpOverlapped);
-                kdata::decrypt_buffer(
-                        rust::Slice((uint8_t*)lpBuffer, (size_t)nNumberOfBytesToRead),
-                        mappingInfo);
-                return ret;
+                        L"[Redir] Entry: {}, pos: {}, size: {}, read: {}",
+                        mappingInfo.idx, pos, nNumberOfBytesToRead, bytesRead));
+
+                if (lpNumberOfBytesRead != nullptr) {
+                    *lpNumberOfBytesRead = (DWORD)bytesRead;
+                }
+                lpOverlapped->Internal = 0;
+                lpOverlapped->InternalHigh = bytesRead;
+                if (lpOverlapped->hEvent != nullptr) {
+                    SetEvent(lpOverlapped->hEvent);
+                }
+                return TRUE;
             } else {
 
             }
@@ -9,6 +9,7 @@
 #include <fstream>
 #include <chrono>
 #include <mutex>
+#include <string_view>
 
 #include "rust/cxx.h"
 
@@ -43,6 +44,16 @@ static const wchar_t* LogLevelToString(LogLevel level) {
     }
 }
 
+// Level names of the `log` crate as resolved by kdata, e.g. `debug`. Debug comes first here,
+// so it also lets the trace records through, which kdata only forwards at the trace level.
+static LogLevel LogLevelFromString(std::string_view level) {
+    if (level == "trace" || level == "debug") return LogLevel::Debug;
+    if (level == "info") return LogLevel::Info;
+    if (level == "warn") return LogLevel::Warn;
+    if (level == "error") return LogLevel::Error;
+    return LogLevel::Silent;
+}
+
 class Logger {
 public:
     static Logger& GetInstance() {